@@ -0,0 +1,5 @@
+//! `cargo macrotest review`: accepts or rejects pending macro expansion snapshots.
+
+fn main() -> std::process::ExitCode {
+    macrotest::__cargo_macrotest_main()
+}
@@ -1,18 +1,115 @@
+use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
+use std::env;
 use std::ffi::OsStr;
+use std::fs;
 use std::io::BufRead;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
 
 use crate::error::{Error, Result};
+use crate::events::OutputFormat;
 use crate::expand::Project;
+use crate::features::Features;
 use crate::manifest::Name;
+use crate::message::{message_hints, Hint};
 use crate::rustflags;
 use serde_derive::Deserialize;
 
+const MACROTEST_RESOLVE: &str = "MACROTEST_RESOLVE";
+
+/// Describes how the dependencies of the generated crate are resolved,
+/// set with the `MACROTEST_RESOLVE` environment variable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Resolution {
+    /// Cargo resolves and fetches the dependencies as needed.
+    Default,
+    /// Passes `--offline` to every cargo invocation.
+    Offline,
+    /// Passes `--locked` to every cargo invocation.
+    Locked,
+    /// Passes `--frozen`, which is `--offline` and `--locked`, to every cargo invocation.
+    Frozen,
+}
+
+impl Resolution {
+    /// Reads the resolution mode from the `MACROTEST_RESOLVE` environment variable.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var_os(MACROTEST_RESOLVE) {
+            Some(ref v) if v == "offline" => Ok(Resolution::Offline),
+            Some(ref v) if v == "locked" => Ok(Resolution::Locked),
+            Some(ref v) if v == "frozen" => Ok(Resolution::Frozen),
+            Some(v) => Err(Error::UnrecognizedEnv(MACROTEST_RESOLVE, v)),
+            None => Ok(Resolution::Default),
+        }
+    }
+
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Resolution::Default => &[],
+            Resolution::Offline => &["--offline"],
+            Resolution::Locked => &["--locked"],
+            Resolution::Frozen => &["--frozen"],
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Metadata {
     pub target_directory: PathBuf,
     pub workspace_root: PathBuf,
+    pub packages: Vec<PackageMetadata>,
+}
+
+/// A package of the workspace as resolved by Cargo, with the inherited fields filled in.
+#[derive(Deserialize, Debug)]
+pub struct PackageMetadata {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub edition: String,
+    pub features: Map<String, Vec<String>>,
+    pub dependencies: Vec<DependencyMetadata>,
+    pub targets: Vec<TargetMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DependencyMetadata {
+    pub name: String,
+    pub source: Option<String>,
+    pub req: String,
+    /// `dev` or `build`, `None` for normal dependencies.
+    pub kind: Option<String>,
+    pub rename: Option<String>,
+    pub optional: bool,
+    pub uses_default_features: bool,
+    pub features: Vec<String>,
+    /// The platform of a target-specific dependency, e.g. `cfg(unix)`.
+    pub target: Option<String>,
+    /// The index of an alternative registry, `None` for crates.io.
+    pub registry: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TargetMetadata {
+    pub name: String,
+    pub kind: Vec<String>,
+}
+
+impl PackageMetadata {
+    /// The name of the library target, which the tests refer to, if the package has one.
+    pub fn lib_name(&self) -> Option<&str> {
+        self.targets
+            .iter()
+            .find(|target| {
+                target
+                    .kind
+                    .iter()
+                    .any(|kind| kind.ends_with("lib") || kind == "proc-macro")
+            })
+            .map(|target| target.name.as_str())
+    }
 }
 
 fn raw_cargo() -> Command {
@@ -23,42 +120,105 @@ fn cargo(project: &Project) -> Command {
     let mut cmd = raw_cargo();
     cmd.current_dir(&project.dir);
     cmd.env("CARGO_TARGET_DIR", &project.inner_target_dir);
-    rustflags::set_env(&mut cmd);
+    rustflags::set_env(&mut cmd, &project.rustflags);
     cmd
 }
 
-pub(crate) fn metadata() -> Result<Metadata> {
+/// Checks that `cargo expand` is installed and supports the flags used by this crate,
+/// so that a broken environment is reported once instead of failing every test.
+///
+/// The check runs once per test binary, later calls return the stored result.
+pub(crate) fn preflight() -> Result<()> {
+    static PREFLIGHT: OnceLock<std::result::Result<(), String>> = OnceLock::new();
+
+    PREFLIGHT
+        .get_or_init(check_cargo_expand)
+        .clone()
+        .map_err(Error::CargoExpandPreflight)
+}
+
+fn check_cargo_expand() -> std::result::Result<(), String> {
+    let output = raw_cargo()
+        .arg("expand")
+        .arg("--help")
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let hints = if !output.status.success() {
+        match Hint::find(&stderr) {
+            hints if hints.is_empty() => vec![Hint::CargoExpandNotInstalled],
+            hints => hints,
+        }
+    } else if !stdout.contains("--theme") {
+        vec![Hint::CargoExpandOutdated]
+    } else {
+        return Ok(());
+    };
+
+    message_hints(&hints);
+    Err(stderr.trim().to_owned())
+}
+
+pub(crate) fn metadata(resolution: Resolution) -> Result<Metadata> {
     let output = raw_cargo()
         .arg("metadata")
         .arg("--format-version=1")
+        .arg("--no-deps")
+        .args(resolution.args())
         .output()
         .map_err(Error::Cargo)?;
 
     serde_json::from_slice(&output.stdout).map_err(Error::CargoMetadata)
 }
 
-pub(crate) fn expand<I, S>(
-    project: &Project,
-    name: &Name,
-    args: &Option<I>,
-) -> Result<(bool, Vec<u8>)>
+/// A diagnostic reported by the compiler, as printed with `--message-format=json`.
+#[derive(Deserialize, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub level: String,
+    pub rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<Diagnostic>,
+}
+
+fn expand_command<I, S>(project: &Project, name: &Name, args: &Option<I>) -> Command
 where
     I: IntoIterator<Item = S> + Clone,
     S: AsRef<OsStr>,
 {
     let mut cargo = cargo(project);
-    let cargo = cargo
+    cargo
         .arg("expand")
         .arg("--bin")
         .arg(name.as_ref())
         .arg("--theme")
-        .arg("none");
+        .arg("none")
+        .args(project.resolution.args());
 
     if let Some(args) = args {
         cargo.args(args.clone());
     }
 
-    let cargo_expand = cargo
+    cargo
+}
+
+pub(crate) fn expand<I, S>(
+    project: &Project,
+    name: &Name,
+    args: &Option<I>,
+) -> Result<(bool, Vec<u8>)>
+where
+    I: IntoIterator<Item = S> + Clone,
+    S: AsRef<OsStr>,
+{
+    let cargo_expand = expand_command(project, name, args)
         .output()
         .map_err(|e| Error::CargoExpandExecution(e.to_string()))?;
 
@@ -69,7 +229,37 @@ where
     Ok((true, cargo_expand.stdout))
 }
 
-/// Builds dependencies for macro expansion and pipes `cargo` output to `STDOUT`.
+/// Re-runs the failed expansion with `--message-format=json` to collect the errors
+/// reported by the compiler.
+///
+/// Returns an empty list if the failure was not caused by compiler errors.
+pub(crate) fn expansion_errors<I, S>(
+    project: &Project,
+    name: &Name,
+    args: &Option<I>,
+) -> Result<Vec<Diagnostic>>
+where
+    I: IntoIterator<Item = S> + Clone,
+    S: AsRef<OsStr>,
+{
+    let output = expand_command(project, name, args)
+        .arg("--message-format=json")
+        .output()
+        .map_err(|e| Error::CargoExpandExecution(e.to_string()))?;
+
+    Ok(output
+        .stdout
+        .lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(&line).ok())
+        .filter(|msg| msg.reason == "compiler-message")
+        .filter_map(|msg| msg.message)
+        .filter(|diag| diag.level == "error" && !diag.message.starts_with("aborting due to"))
+        .collect())
+}
+
+/// Builds dependencies for macro expansion and pipes `cargo` output to `STDOUT`,
+/// unless the JSON output format is used.
 /// Tries to expand macros in `main.rs` and intentionally filters the result.
 /// This function is called before macro expansions to speed them up and
 /// for dependencies build process to be visible for user.
@@ -82,6 +272,8 @@ pub(crate) fn build_dependencies(project: &Project) -> Result<()> {
         .arg(project.name.clone())
         .arg("--theme")
         .arg("none")
+        .args(project.resolution.args())
+        .args(project.features.iter().flat_map(Features::args))
         .stdout(std::process::Stdio::piped())
         .spawn()?
         .stdout
@@ -95,6 +287,7 @@ pub(crate) fn build_dependencies(project: &Project) -> Result<()> {
         .filter_map(|line| line.ok())
         .filter(|line| !line.starts_with("fn main() {}"))
         .filter(|line| !line_should_be_ignored(line))
+        .filter(|_| project.format == OutputFormat::Human)
         .for_each(|line| {
             let _ = writeln!(std::io::stdout(), "{}", line);
         });
@@ -102,6 +295,86 @@ pub(crate) fn build_dependencies(project: &Project) -> Result<()> {
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct LockedPackage {
+    name: String,
+    version: String,
+}
+
+fn locked_packages(lockfile: &Path) -> Result<Set<LockedPackage>> {
+    let lockfile = match fs::read_to_string(lockfile) {
+        Ok(lockfile) => lockfile,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Set::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let lockfile: Lockfile = toml::de::from_str(&lockfile)?;
+    Ok(lockfile.package.into_iter().collect())
+}
+
+/// Checks that the lockfile copied from the source workspace covers the generated crate,
+/// so that the cargo invocations don't need to resolve the dependencies again.
+///
+/// The generated crate is resolved without `--locked`, which keeps the locked versions and only
+/// adds the generated crate itself to the lockfile, unless some packages are missing from it.
+/// With `--locked` or `--frozen`, the missing packages are reported as an error, instead of
+/// the error from cargo that only says that the lockfile needs to be updated.
+///
+/// The resolution is done offline only if offline builds are requested: with `--locked` alone,
+/// the registry may still have to be fetched, e.g. on a machine with an empty cache.
+pub(crate) fn resolve(project: &Project, dependencies: &[String]) -> Result<()> {
+    if project.resolution == Resolution::Default {
+        return Ok(());
+    }
+
+    let lockfile = path!(project.dir / "Cargo.lock");
+    let locked = locked_packages(&lockfile)?;
+
+    let offline = match project.resolution {
+        Resolution::Offline | Resolution::Frozen => Some("--offline"),
+        Resolution::Default | Resolution::Locked => None,
+    };
+    let output = cargo(project)
+        .arg("metadata")
+        .arg("--format-version=1")
+        .args(offline)
+        .output()
+        .map_err(Error::Cargo)?;
+
+    let missing = if output.status.success() {
+        locked_packages(&lockfile)?
+            .difference(&locked)
+            .filter(|package| package.name != project.name)
+            .map(|package| format!("{} v{}", package.name, package.version))
+            .collect::<Vec<_>>()
+    } else {
+        // The resolution failed, so only the direct dependencies can be checked.
+        dependencies
+            .iter()
+            .filter(|dependency| !locked.iter().any(|package| package.name == **dependency))
+            .cloned()
+            .collect()
+    };
+
+    if missing.is_empty() {
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Resolve(stderr.trim().to_owned()));
+    }
+
+    match project.resolution {
+        Resolution::Offline if output.status.success() => Ok(()),
+        _ => Err(Error::MissingFromLockfile(missing)),
+    }
+}
+
 const IGNORED_LINES: [&str; 5] = [
     "#![feature(prelude_import)]",
     "#[prelude_import]",
@@ -0,0 +1,103 @@
+use std::env;
+
+use crate::error::{Error, Result};
+use quote::ToTokens;
+use syn::Item;
+
+const MACROTEST_COMPARE: &str = "MACROTEST_COMPARE";
+
+/// Describes how an expansion result is compared with the content of `.expanded.rs` file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Comparison {
+    /// Line by line comparison of the formatted code.
+    Text,
+    /// Both sides are parsed with `syn` and compared as token streams,
+    /// so formatting and comments are ignored.
+    Ast,
+    /// Same as [`Comparison::Ast`], but the order of top-level items doesn't matter.
+    /// If `nested` is set, the order of items inside inline modules doesn't matter as well.
+    UnorderedItems { nested: bool },
+}
+
+impl Comparison {
+    /// Reads the comparison mode from the `MACROTEST_COMPARE` environment variable.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var_os(MACROTEST_COMPARE) {
+            Some(ref v) if v == "text" => Ok(Comparison::Text),
+            Some(ref v) if v == "ast" => Ok(Comparison::Ast),
+            Some(ref v) if v == "unordered" => Ok(Comparison::UnorderedItems { nested: false }),
+            Some(ref v) if v == "unordered-nested" => {
+                Ok(Comparison::UnorderedItems { nested: true })
+            }
+            Some(v) => Err(Error::UnrecognizedEnv(MACROTEST_COMPARE, v)),
+            None => Ok(Comparison::Text),
+        }
+    }
+
+    /// Brings the items of the expansion into a canonical order if their order doesn't matter.
+    pub(crate) fn canonicalize(self, file: &mut syn::File) {
+        if let Comparison::UnorderedItems { nested } = self {
+            sort_items(&mut file.items, nested);
+        }
+    }
+}
+
+fn sort_items(items: &mut [Item], nested: bool) {
+    if nested {
+        for item in items.iter_mut() {
+            if let Item::Mod(module) = item {
+                if let Some((_, content)) = &mut module.content {
+                    sort_items(content, nested);
+                }
+            }
+        }
+    }
+
+    items.sort_by_cached_key(|item| item.to_token_stream().to_string());
+}
+
+/// Returns `true` if the expected and the actual expansions are considered the same.
+pub(crate) fn same(comparison: Comparison, expected: &str, actual: &str) -> bool {
+    if actual.lines().eq(expected.lines()) {
+        return true;
+    }
+
+    match comparison {
+        Comparison::Text => false,
+        Comparison::Ast | Comparison::UnorderedItems { .. } => {
+            match (syn::parse_file(expected), syn::parse_file(actual)) {
+                (Ok(mut expected), Ok(mut actual)) => {
+                    // Once sorted, comparing items in order is the same as comparing multisets.
+                    comparison.canonicalize(&mut expected);
+                    comparison.canonicalize(&mut actual);
+
+                    // Comments are not part of the token stream, and the token stream is
+                    // printed without any regard to the original formatting.
+                    expected.into_token_stream().to_string()
+                        == actual.into_token_stream().to_string()
+                }
+                // Fall back to the text comparison if either side can't be parsed.
+                _ => false,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_ast_comparison() {
+    let expected = "fn main() {\n    let x = vec![1, 2, 3];\n}\n";
+    let reflowed = "// comment\nfn main() {\n    let x = vec![\n        1,\n        2,\n        3\n    ];\n}\n";
+
+    assert!(!same(Comparison::Text, expected, reflowed));
+    assert!(same(Comparison::Ast, expected, reflowed));
+    assert!(!same(Comparison::Ast, expected, "fn main() {}\n"));
+
+    let unordered = Comparison::UnorderedItems { nested: false };
+    let nested = Comparison::UnorderedItems { nested: true };
+    let expected = "struct A;\nmod m {\n    struct B;\n    struct C;\n}\nstruct D;\n";
+    let reordered = "struct D;\nmod m {\n    struct C;\n    struct B;\n}\nstruct A;\n";
+
+    assert!(!same(Comparison::Ast, expected, reordered));
+    assert!(!same(unordered, expected, reordered));
+    assert!(same(nested, expected, reordered));
+}
@@ -0,0 +1,196 @@
+use crate::error::Error;
+use std::collections::BTreeMap as Map;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// The sections of the cargo configuration that are carried over to the generated crate.
+const SECTIONS: &[&str] = &["env", "net", "registries", "registry", "source", "target"];
+
+/// Returns the sections of the cargo configuration that apply to the source crate,
+/// merged in the same order of precedence as Cargo does.
+///
+/// The generated crate lives in the target directory, which may be outside of the source
+/// workspace, so the configuration files found from there would otherwise be missed.
+/// Refs: https://doc.rust-lang.org/cargo/reference/config.html#hierarchical-structure
+pub(crate) fn get_source_config(source_dir: &Path) -> Map<String, Value> {
+    get_config(source_dir, SECTIONS, None)
+}
+
+/// Returns the `build` and `target` sections of the cargo configuration that apply to the
+/// source crate, including the configuration in the cargo home directory.
+pub(crate) fn get_build_config(source_dir: &Path) -> Map<String, Value> {
+    get_config(source_dir, &["build", "target"], cargo_home().as_deref())
+}
+
+fn get_config(source_dir: &Path, sections: &[&str], home: Option<&Path>) -> Map<String, Value> {
+    let mut config = Map::new();
+
+    // Files closer to the source crate take precedence, so they're merged last.
+    let mut files = source_dir
+        .ancestors()
+        .filter_map(config_file)
+        .collect::<Vec<_>>();
+    if let Some((file, dir)) = home.and_then(home_config_file) {
+        if !files.iter().any(|(found, _)| *found == file) {
+            files.push((file, dir));
+        }
+    }
+    files.reverse();
+
+    for (file, dir) in files {
+        if let Ok(mut table) = try_get_config(&file) {
+            table.retain(|section, _| sections.contains(&section.as_str()));
+            fix_paths(&mut table, dir);
+            for (section, value) in table {
+                match config.get_mut(&section) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        config.insert(section, value);
+                    }
+                }
+            }
+        }
+    }
+
+    config
+}
+
+/// Returns the configuration file in `dir`, along with `dir`, which relative paths in the
+/// file are resolved against. `.cargo/config` is the legacy name of `.cargo/config.toml`.
+fn config_file(dir: &Path) -> Option<(PathBuf, &Path)> {
+    ["config.toml", "config"]
+        .iter()
+        .map(|name| dir.join(".cargo").join(name))
+        .find(|file| file.is_file())
+        .map(|file| (file, dir))
+}
+
+/// Returns the configuration file in the cargo home directory, whose relative paths are
+/// resolved against the parent of the cargo home directory, as for `.cargo` directories.
+fn home_config_file(home: &Path) -> Option<(PathBuf, &Path)> {
+    let dir = home.parent()?;
+    ["config.toml", "config"]
+        .iter()
+        .map(|name| home.join(name))
+        .find(|file| file.is_file())
+        .map(|file| (file, dir))
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))
+}
+
+fn try_get_config(file: &Path) -> Result<Map<String, Value>, Error> {
+    let config_str = fs::read_to_string(file)?;
+    let config: Map<String, Value> = toml::de::from_str(&config_str)?;
+
+    Ok(config)
+}
+
+/// Merges `value` into `existing`, with `value` taking precedence: tables are merged
+/// recursively and arrays are joined, other values are replaced.
+fn merge(existing: &mut Value, value: Value) {
+    match (existing, value) {
+        (Value::Table(existing), Value::Table(table)) => {
+            for (key, value) in table {
+                match existing.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        existing.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(existing), Value::Array(array)) => existing.extend(array),
+        (existing, value) => *existing = value,
+    }
+}
+
+/// Makes the paths that Cargo resolves relative to the directory containing `.cargo`
+/// absolute, as the generated configuration lives in another directory.
+fn fix_paths(config: &mut Map<String, Value>, dir: &Path) {
+    let join = |value: &mut Value| {
+        if let Value::String(path) = value {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
+        }
+    };
+
+    if let Some(Value::Table(sources)) = config.get_mut("source") {
+        for (_, source) in sources.iter_mut() {
+            if let Value::Table(source) = source {
+                for key in ["directory", "local-registry"] {
+                    if let Some(path) = source.get_mut(key) {
+                        join(path);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(Value::Table(env)) = config.get_mut("env") {
+        for (_, var) in env.iter_mut() {
+            if let Value::Table(var) = var {
+                if var.get("relative").and_then(Value::as_bool) == Some(true) {
+                    if let Some(value) = var.get_mut("value") {
+                        join(value);
+                    }
+                    var.remove("relative");
+                }
+            }
+        }
+    }
+
+    if let Some(Value::Table(targets)) = config.get_mut("target") {
+        for (_, target) in targets.iter_mut() {
+            if let Value::Table(target) = target {
+                // A linker given as a bare program name is looked up in `PATH`.
+                if let Some(linker) = target.get_mut("linker") {
+                    if linker.as_str().is_some_and(|linker| linker.contains('/')) {
+                        join(linker);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_merge() {
+    let mut config = toml::from_str::<Value>(
+        r#"
+        [net]
+        offline = false
+        [target.x86_64-unknown-linux-gnu]
+        rustflags = ["-C", "opt-level=1"]
+        "#,
+    )
+    .unwrap();
+    let closer = toml::from_str::<Value>(
+        r#"
+        [net]
+        offline = true
+        retry = 3
+        [target.x86_64-unknown-linux-gnu]
+        rustflags = ["--cfg", "closer"]
+        "#,
+    )
+    .unwrap();
+
+    merge(&mut config, closer);
+
+    let expected = toml::from_str::<Value>(
+        r#"
+        [net]
+        offline = true
+        retry = 3
+        [target.x86_64-unknown-linux-gnu]
+        rustflags = ["-C", "opt-level=1", "--cfg", "closer"]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config, expected);
+}
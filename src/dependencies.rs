@@ -1,12 +1,8 @@
+use crate::cargo::DependencyMetadata;
 use crate::error::Error;
-use crate::manifest::Edition;
-use serde::de::value::MapAccessDeserializer;
-use serde::de::{self, Deserialize, Deserializer, Visitor};
-use serde::ser::{Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::BTreeMap as Map;
-use std::fmt;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -18,10 +14,7 @@ pub(crate) fn get_manifest(manifest_dir: &Path) -> Manifest {
 fn try_get_manifest(manifest_dir: &Path) -> Result<Manifest, Error> {
     let cargo_toml_path = manifest_dir.join("Cargo.toml");
     let manifest_str = fs::read_to_string(cargo_toml_path)?;
-    let mut manifest: Manifest = toml::de::from_str(&manifest_str)?;
-
-    fix_dependencies(&mut manifest.dependencies, manifest_dir);
-    fix_dependencies(&mut manifest.dev_dependencies, manifest_dir);
+    let manifest: Manifest = toml::de::from_str(&manifest_str)?;
 
     Ok(manifest)
 }
@@ -35,17 +28,70 @@ pub(crate) fn try_get_workspace_manifest(manifest_dir: &Path) -> Result<Workspac
     let manifest_str = fs::read_to_string(cargo_toml_path)?;
     let mut manifest: WorkspaceManifest = toml::de::from_str(&manifest_str)?;
 
-    fix_dependencies(&mut manifest.workspace.dependencies, manifest_dir);
     fix_patches(&mut manifest.patch, manifest_dir);
     fix_replacements(&mut manifest.replace, manifest_dir);
 
     Ok(manifest)
 }
 
-fn fix_dependencies(dependencies: &mut Map<String, Dependency>, dir: &Path) {
-    dependencies.remove("macrotest");
-    for dep in dependencies.values_mut() {
-        dep.path = dep.path.as_ref().map(|path| dir.join(path));
+/// Converts a dependency resolved by `cargo metadata` into an entry of the generated manifest.
+///
+/// Returns the name of the dependency as seen by the code, which differs from the package name
+/// if the dependency is renamed.
+pub(crate) fn from_metadata(dep: &DependencyMetadata) -> (String, Dependency) {
+    let mut rest = Map::new();
+    if dep.optional {
+        rest.insert("optional".to_owned(), Value::Bool(true));
+    }
+    if dep.rename.is_some() {
+        rest.insert("package".to_owned(), Value::String(dep.name.clone()));
+    }
+    if let Some(git) = dep.source.as_deref().and_then(|s| s.strip_prefix("git+")) {
+        let git = git.split('#').next().unwrap_or(git);
+        let (url, query) = git.split_once('?').unwrap_or((git, ""));
+        rest.insert("git".to_owned(), Value::String(url.to_owned()));
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            if let "branch" | "tag" | "rev" = key {
+                rest.insert(key.to_owned(), Value::String(value.to_owned()));
+            }
+        }
+    }
+    if let Some(registry) = &dep.registry {
+        rest.insert("registry-index".to_owned(), Value::String(registry.clone()));
+    }
+
+    let dependency = Dependency {
+        version: Some(dep.req.clone()),
+        path: dep.path.clone(),
+        default_features: dep.uses_default_features,
+        features: dep.features.clone(),
+        rest,
+    };
+    let name = dep.rename.clone().unwrap_or_else(|| dep.name.clone());
+    (name, dependency)
+}
+
+/// Removes the `dep:` entries of the features whose dependency is not optional
+/// in the generated manifest.
+///
+/// `cargo metadata` reports the implicit feature of each optional dependency, e.g.
+/// `serde = ["dep:serde"]`, but a dev-dependency of the same name replaces the optional one
+/// in the generated manifest, and Cargo rejects a feature that enables a required dependency.
+pub(crate) fn retain_optional(
+    features: &mut Map<String, Vec<String>>,
+    dependencies: &[&Map<String, Dependency>],
+) {
+    let is_optional = |name: &str| {
+        dependencies
+            .iter()
+            .filter_map(|dependencies| dependencies.get(name))
+            .any(Dependency::is_optional)
+    };
+    for deps in features.values_mut() {
+        deps.retain(|dep| match dep.strip_prefix("dep:") {
+            Some(name) => is_optional(name),
+            None => true,
+        });
     }
 }
 
@@ -65,70 +111,42 @@ fn fix_replacements(replacements: &mut Map<String, Patch>, dir: &Path) {
     }
 }
 
+/// The sections of the workspace manifest that `cargo metadata` doesn't report.
 #[derive(Deserialize, Default, Debug)]
 pub struct WorkspaceManifest {
-    #[serde(default)]
-    pub workspace: Workspace,
     #[serde(default)]
     pub patch: Map<String, RegistryPatch>,
     #[serde(default)]
     pub replace: Map<String, Patch>,
 }
 
-#[derive(Deserialize, Default, Debug)]
-pub struct Workspace {
-    #[serde(default)]
-    pub package: WorkspacePackage,
-    #[serde(default)]
-    pub dependencies: Map<String, Dependency>,
-}
-
-#[derive(Deserialize, Default, Debug)]
-pub struct WorkspacePackage {
-    pub edition: Option<String>,
-}
-
+/// The sections of the package manifest that `cargo metadata` doesn't report.
 #[derive(Deserialize, Default, Debug)]
 pub struct Manifest {
     #[serde(default, rename = "cargo-features")]
     pub cargo_features: Vec<String>,
-    #[serde(default)]
-    pub package: Package,
-    #[serde(default)]
-    pub features: Map<String, Vec<String>>,
-    #[serde(default)]
-    pub dependencies: Map<String, Dependency>,
-    #[serde(default, alias = "dev-dependencies")]
-    pub dev_dependencies: Map<String, Dependency>,
-}
-
-#[derive(Deserialize, Default, Debug)]
-pub struct Package {
-    #[serde(default)]
-    pub edition: Edition,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(remote = "Self")]
+#[derive(Serialize, Clone, Debug)]
 pub struct Dependency {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<PathBuf>,
-    #[serde(
-        rename = "default-features",
-        default = "get_true",
-        skip_serializing_if = "is_true"
-    )]
+    #[serde(rename = "default-features", skip_serializing_if = "is_true")]
     pub default_features: bool,
-    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub features: Vec<String>,
-    #[serde(default, skip_serializing_if = "is_false")]
-    pub workspace: bool,
     #[serde(flatten)]
     pub rest: Map<String, Value>,
 }
 
+impl Dependency {
+    fn is_optional(&self) -> bool {
+        self.rest.get("optional") == Some(&Value::Bool(true))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(transparent)]
 pub struct RegistryPatch {
@@ -145,68 +163,44 @@ pub struct Patch {
     pub branch: Option<String>,
 }
 
-fn get_true() -> bool {
-    true
-}
-
 #[allow(clippy::trivially_copy_pass_by_ref)]
 fn is_true(boolean: &bool) -> bool {
     *boolean
 }
 
-#[allow(clippy::trivially_copy_pass_by_ref)]
-fn is_false(boolean: &bool) -> bool {
-    !*boolean
-}
-
-impl Serialize for Dependency {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        Dependency::serialize(self, serializer)
-    }
-}
-
-impl<'de> Deserialize<'de> for Dependency {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        struct DependencyVisitor;
-
-        impl<'de> Visitor<'de> for DependencyVisitor {
-            type Value = Dependency;
-
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str(
-                    "a version string like \"0.9.8\" or a \
-                     dependency like { version = \"0.9.8\" }",
-                )
-            }
-
-            fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
-            where
-                E: de::Error,
-            {
-                Ok(Dependency {
-                    version: Some(s.to_owned()),
-                    path: None,
-                    default_features: true,
-                    features: Vec::new(),
-                    workspace: false,
-                    rest: Map::new(),
-                })
-            }
-
-            fn visit_map<M>(self, map: M) -> Result<Self::Value, M::Error>
-            where
-                M: de::MapAccess<'de>,
-            {
-                Dependency::deserialize(MapAccessDeserializer::new(map))
-            }
-        }
-
-        deserializer.deserialize_any(DependencyVisitor)
-    }
+#[test]
+fn test_optional_and_dev_dependency() {
+    let dependency = |kind: &str, optional: bool| {
+        serde_json::from_value::<DependencyMetadata>(serde_json::json!({
+            "name": "serde",
+            "source": "registry+https://github.com/rust-lang/crates.io-index",
+            "req": "^1",
+            "kind": if kind.is_empty() { Value::Null } else { Value::from(kind) },
+            "rename": null,
+            "optional": optional,
+            "uses_default_features": true,
+            "features": [],
+            "target": null,
+            "registry": null,
+            "path": null,
+        }))
+        .unwrap()
+    };
+
+    let mut features = Map::new();
+    features.insert("serde".to_owned(), vec!["dep:serde".to_owned()]);
+
+    // The optional dependency alone keeps its implicit feature.
+    let mut dependencies = Map::new();
+    let (name, optional) = from_metadata(&dependency("", true));
+    dependencies.insert(name, optional);
+    let mut retained = features.clone();
+    retain_optional(&mut retained, &[&dependencies]);
+    assert_eq!(retained["serde"], ["dep:serde"]);
+
+    // The dev-dependency replaces it, so the feature must not enable it anymore.
+    let (name, dev) = from_metadata(&dependency("dev", false));
+    dependencies.insert(name, dev);
+    retain_optional(&mut features, &[&dependencies]);
+    assert!(features["serde"].is_empty());
 }
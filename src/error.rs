@@ -2,6 +2,7 @@
 pub(crate) enum Error {
     Cargo(std::io::Error),
     CargoExpandExecution(String),
+    CargoExpandPreflight(String),
     CargoFail,
     CargoMetadata(serde_json::error::Error),
     Io(std::io::Error),
@@ -9,9 +10,16 @@ pub(crate) enum Error {
     TomlDe(toml::de::Error),
     Glob(glob::GlobError),
     GlobPattern(glob::PatternError),
+    InvalidUtf8(std::path::PathBuf, std::str::Utf8Error),
     ManifestDir,
+    MissingFromLockfile(Vec<String>),
     PkgName,
-    UnrecognizedEnv(std::ffi::OsString),
+    PackageMetadata,
+    Resolve(String),
+    Rustfmt(String),
+    RustfmtNotFound,
+    UnrecognizedEnv(&'static str, std::ffi::OsString),
+    Usage(String),
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
@@ -23,6 +31,7 @@ impl std::fmt::Display for Error {
         match self {
             Cargo(e) => write!(f, "{}", e),
             CargoExpandExecution(e) => write!(f, "Failed to execute cargo command: {}", e),
+            CargoExpandPreflight(e) => write!(f, "cargo expand is not usable: {}", e),
             CargoFail => write!(f, "cargo reported an error"),
             CargoMetadata(e) => write!(f, "{}", e),
             Io(e) => write!(f, "{}", e),
@@ -30,13 +39,32 @@ impl std::fmt::Display for Error {
             TomlDe(e) => write!(f, "{}", e),
             Glob(e) => write!(f, "{}", e),
             GlobPattern(e) => write!(f, "{}", e),
+            InvalidUtf8(path, e) => write!(f, "{} is not valid UTF-8: {}", path.display(), e),
             ManifestDir => write!(f, "could not find CARGO_MANIFEST_DIR env var"),
+            MissingFromLockfile(packages) => write!(
+                f,
+                "packages required by the tests are missing from Cargo.lock: {}\n\
+                 update Cargo.lock of the workspace, or unset MACROTEST_RESOLVE",
+                packages.join(", ")
+            ),
             PkgName => write!(f, "could not find CARGO_PKG_NAME env var"),
-            UnrecognizedEnv(e) => write!(
+            PackageMetadata => write!(
+                f,
+                "could not find the crate in the output of cargo metadata"
+            ),
+            Resolve(e) => write!(f, "could not resolve the dependencies of the tests: {}", e),
+            Rustfmt(e) => write!(f, "rustfmt failed: {}", e),
+            RustfmtNotFound => write!(
+                f,
+                "rustfmt is not installed, install it by running `rustup component add rustfmt`"
+            ),
+            UnrecognizedEnv(var, e) => write!(
                 f,
-                "unrecognized value of MACROTEST: \"{}\"",
+                "unrecognized value of {}: \"{}\"",
+                var,
                 e.to_string_lossy()
             ),
+            Usage(e) => write!(f, "{}", e),
         }
     }
 }
@@ -0,0 +1,120 @@
+use std::env;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::expand::{ExpansionOutcome, TestRecord};
+use crate::message::format_different;
+use crate::report::expansion_error;
+use serde_derive::Serialize;
+
+const MACROTEST_FORMAT: &str = "MACROTEST_FORMAT";
+
+/// Describes how the progress of the expansion tests is reported on `STDOUT`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum OutputFormat {
+    /// Human-readable messages.
+    Human,
+    /// One JSON object per line for each event, see [`Event`].
+    Json,
+}
+
+impl OutputFormat {
+    /// Reads the output format from the `MACROTEST_FORMAT` environment variable.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var_os(MACROTEST_FORMAT) {
+            Some(ref v) if v == "human" => Ok(OutputFormat::Human),
+            Some(ref v) if v == "json" => Ok(OutputFormat::Json),
+            Some(v) => Err(Error::UnrecognizedEnv(MACROTEST_FORMAT, v)),
+            None => Ok(OutputFormat::Human),
+        }
+    }
+}
+
+/// An event of the JSON output format.
+///
+/// Every event carries the glob pattern passed to the `expand` function as `suite`,
+/// because events of the tests running in parallel are interleaved.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    RunStarted {
+        suite: &'a str,
+        tests: usize,
+        filtered_out: usize,
+    },
+    BuildStarted {
+        suite: &'a str,
+    },
+    BuildFinished {
+        suite: &'a str,
+        success: bool,
+        duration: f64,
+    },
+    Test {
+        suite: &'a str,
+        path: &'a Path,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        feature_set: Option<&'a str>,
+        expanded: &'a Path,
+        outcome: &'static str,
+        duration: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    Orphan {
+        suite: &'a str,
+        path: &'a Path,
+        removed: bool,
+    },
+    RunFinished {
+        suite: &'a str,
+        passed: usize,
+        failed: usize,
+        refreshed: usize,
+        duration: f64,
+    },
+}
+
+impl Event<'_> {
+    /// Prints the event as a single line of JSON if the JSON output format is selected.
+    pub(crate) fn emit(&self, format: OutputFormat) {
+        if format != OutputFormat::Json {
+            return;
+        }
+
+        if let Ok(json) = serde_json::to_string(self) {
+            let _ = writeln!(std::io::stdout(), "{}", json);
+        }
+    }
+}
+
+/// Makes an event describing the outcome of a single test.
+pub(crate) fn test_event<'a>(suite: &'a str, record: &'a TestRecord, context: usize) -> Event<'a> {
+    let (outcome, message) = match &record.result {
+        Ok(ExpansionOutcome::Same) => ("ok", None),
+        Ok(ExpansionOutcome::Update) => ("refreshed", None),
+        Ok(ExpansionOutcome::Different(a, b)) => {
+            ("different", Some(format_different(a, b, context, false)))
+        }
+        Ok(ExpansionOutcome::ExpandError(stderr, errors)) => {
+            ("expand_error", Some(expansion_error(stderr, errors)))
+        }
+        Ok(ExpansionOutcome::ParseError(error, _)) => ("parse_error", Some(error.to_string())),
+        Ok(ExpansionOutcome::NoExpandedFileFound) => ("missing", None),
+        Ok(ExpansionOutcome::ChangedByFeatures(a, b)) => {
+            ("changed", Some(format_different(a, b, context, false)))
+        }
+        Err(e) => ("error", Some(e.to_string())),
+    };
+
+    Event::Test {
+        suite,
+        path: &record.test,
+        feature_set: record.feature_set.as_deref(),
+        expanded: &record.expanded,
+        outcome,
+        duration: record.duration.as_secs_f64(),
+        message,
+    }
+}
@@ -1,16 +1,32 @@
 use std::env;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io::Write;
 use std::iter;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use crate::cargo;
+use crate::cargo::{self, PackageMetadata, Resolution};
+use crate::compare::{self, Comparison};
+use crate::config;
 use crate::dependencies::{self, Dependency};
-use crate::features;
+use crate::events::{self, Event, OutputFormat};
+use crate::features::{self, Features};
+use crate::filter::Filter;
+use crate::format::{self, Formatter};
+use crate::html;
+use crate::junit;
 use crate::manifest::{Bin, Build, Config, Manifest, Name, Package, Workspace};
-use crate::message::{message_different, message_expansion_error};
+use crate::message::{
+    message_all_filtered_out, message_check_mode, message_different, message_expansion_error,
+    message_feature_changes, message_features_not_detected, message_orphan, message_parse_error,
+    message_parse_warning, message_pending,
+};
+use crate::powerset;
+use crate::report;
 use crate::rustflags;
+use crate::snapshot;
+use crate::store::{Store, StoreKind};
 use crate::{error::Error, error::Result};
 use syn::punctuated::Punctuated;
 use syn::{Item, Meta, Token};
@@ -21,13 +37,30 @@ const EXPANDED_RS_SUFFIX: &str = "expanded.rs";
 #[derive(Debug)]
 pub(crate) struct Project {
     pub dir: PathBuf,
-    source_dir: PathBuf,
+    pub source_dir: PathBuf,
     /// Used for the inner runs of cargo()
     pub inner_target_dir: PathBuf,
     pub name: String,
-    pub features: Option<Vec<String>>,
+    pub crate_name: String,
+    pub features: Option<Features>,
+    /// The features declared by the crate, from `cargo metadata`.
+    crate_features: Vec<String>,
+    pub edition: Option<String>,
     workspace: PathBuf,
-    overwrite: bool,
+    mode: Mode,
+    comparison: Comparison,
+    pub formatter: Formatter,
+    fail_on_parse_error: bool,
+    diff_context: usize,
+    junit: Option<PathBuf>,
+    html_report: PathBuf,
+    fail_on_orphans: bool,
+    pending_dir: Option<PathBuf>,
+    store: StoreKind,
+    pub resolution: Resolution,
+    /// The rustflags of the source crate along with the ignored lints.
+    pub rustflags: Vec<String>,
+    pub format: OutputFormat,
 }
 
 /// This `Drop` implementation will clean up the temporary crates when expansion is finished.
@@ -58,7 +91,7 @@ pub fn expand(path: impl AsRef<Path>) {
     run_tests(
         path,
         ExpansionBehavior::RegenerateFiles,
-        Option::<Vec<String>>::None,
+        vec![Variant::default()],
     );
 }
 
@@ -70,7 +103,11 @@ where
     I: IntoIterator<Item = S> + Clone,
     S: AsRef<OsStr>,
 {
-    run_tests(path, ExpansionBehavior::RegenerateFiles, Some(args));
+    run_tests(
+        path,
+        ExpansionBehavior::RegenerateFiles,
+        vec![Variant::with_args(args)],
+    );
 }
 
 /// Attempts to expand macros in files that match glob pattern.
@@ -90,7 +127,7 @@ pub fn expand_without_refresh(path: impl AsRef<Path>) {
     run_tests(
         path,
         ExpansionBehavior::ExpectFiles,
-        Option::<Vec<String>>::None,
+        vec![Variant::default()],
     );
 }
 
@@ -102,7 +139,232 @@ where
     I: IntoIterator<Item = S> + Clone,
     S: AsRef<OsStr>,
 {
-    run_tests(path, ExpansionBehavior::ExpectFiles, Some(args));
+    run_tests(
+        path,
+        ExpansionBehavior::ExpectFiles,
+        vec![Variant::with_args(args)],
+    );
+}
+
+/// Attempts to expand macros in files that match glob pattern once for each of the named
+/// feature sets.
+///
+/// The expansion of `<stem>.rs` with the feature set `<name>` is stored in
+/// `<stem>.<name>.expanded.rs`. Each set is expanded with `--no-default-features`, so `default`
+/// has to be listed to enable the default features. All sets share the same temporary crate.
+///
+/// ```rust
+/// # /*
+/// #[test]
+/// # */
+/// pub fn pass() {
+///     macrotest::expand_feature_sets(
+///         "tests/expand/*.rs",
+///         &[
+///             ("default", &["default"]),
+///             ("serde", &["default", "serde"]),
+///             ("no-std", &[]),
+///         ],
+///     );
+/// }
+/// ```
+///
+/// # Refresh behavior
+///
+/// Same as in [`expand`].
+///
+/// # Panics
+///
+/// Will panic if a feature set name is empty or contains characters other than ASCII letters,
+/// digits, `-` and `_`. Otherwise it will exhibit the same behavior as in [`expand`].
+///
+/// [`expand`]: expand/fn.expand.html
+pub fn expand_feature_sets(path: impl AsRef<Path>, feature_sets: &[(&str, &[&str])]) {
+    run_tests(
+        path,
+        ExpansionBehavior::RegenerateFiles,
+        Variant::feature_sets(feature_sets),
+    );
+}
+
+/// Same as [`expand_feature_sets`] but fails if the `.expanded.rs` files are not present,
+/// like [`expand_without_refresh`].
+///
+/// [`expand_feature_sets`]: expand/fn.expand_feature_sets.html
+/// [`expand_without_refresh`]: expand/fn.expand_without_refresh.html
+pub fn expand_feature_sets_without_refresh(
+    path: impl AsRef<Path>,
+    feature_sets: &[(&str, &[&str])],
+) {
+    run_tests(
+        path,
+        ExpansionBehavior::ExpectFiles,
+        Variant::feature_sets(feature_sets),
+    );
+}
+
+/// Expands macros in files that match glob pattern under combinations of the features
+/// of the crate and reports which of them change the expansion.
+///
+/// The features are read from the `[features]` table of the crate's `Cargo.toml`. Each test
+/// file is expanded with the default features first, and then with every combination of the
+/// other features enabled on top of them. If the crate has more than 6 features, each of them
+/// is enabled individually instead.
+///
+/// No `.expanded.rs` files are read or written: use [`expand`] or [`expand_feature_sets`]
+/// to test the expansion itself.
+/// The combinations that change the expansion are reported in the JSON output and in the
+/// reports with the `changed` outcome, which is not a failure.
+///
+/// # Panics
+///
+/// Will panic if the expansion fails with the default features.
+///
+/// [`expand`]: expand/fn.expand.html
+/// [`expand_feature_sets`]: expand/fn.expand_feature_sets.html
+pub fn expand_feature_powerset(path: impl AsRef<Path>) {
+    let run = Run::new(path.as_ref());
+    let project = run.prepare();
+
+    let features = project
+        .crate_features
+        .iter()
+        .filter(|feature| *feature != "default")
+        .cloned()
+        .collect::<Vec<_>>();
+    if features.len() > powerset::MAX_POWERSET_FEATURES {
+        eprintln!(
+            "{} features found, expanding each of them individually",
+            features.len()
+        );
+    }
+    let variants = iter::once(Variant {
+        name: None,
+        args: None,
+        selects_features: true,
+    })
+    .chain(
+        powerset::combinations(&features, powerset::MAX_POWERSET_FEATURES)
+            .into_iter()
+            .map(|combination| {
+                let combination = combination.join(",");
+                Variant {
+                    name: Some(combination.clone()),
+                    args: Some(vec![
+                        OsString::from("--features"),
+                        OsString::from(combination),
+                    ]),
+                    selects_features: true,
+                }
+            }),
+    )
+    .collect::<Vec<_>>();
+
+    let len = run.tests.len() * variants.len();
+    run.start(len, run.filtered_out * variants.len());
+    run.build(&project);
+
+    let mut failures = 0;
+    let mut records = Vec::with_capacity(len);
+    for test in &run.tests {
+        let path = test.test.display().to_string();
+        let mut test_records = Vec::with_capacity(variants.len());
+        let mut baseline = None;
+        for variant in &variants {
+            let start = Instant::now();
+            let expansion = test.expand(&project, variant);
+            let duration = start.elapsed();
+
+            let result = match (expansion, &baseline) {
+                (Ok(Expansion::Code(code)), None) => {
+                    baseline = Some(code);
+                    Ok(ExpansionOutcome::Same)
+                }
+                (Ok(expansion), Some(baseline)) => {
+                    Ok(compare_to_baseline(project.comparison, baseline, expansion))
+                }
+                (Ok(Expansion::Failed(outcome)), None) => Ok(outcome),
+                (Err(e), _) => Err(e),
+            };
+
+            let record = TestRecord {
+                test: test.test.clone(),
+                feature_set: variant.name.clone(),
+                expanded: test.expanded_path(variant),
+                duration,
+                result,
+            };
+            events::test_event(&run.suite, &record, project.diff_context).emit(run.format);
+            test_records.push(record);
+
+            // Nothing to compare the other combinations with.
+            if baseline.is_none() {
+                break;
+            }
+        }
+
+        if baseline.is_none() {
+            match &test_records[0].result {
+                Ok(ExpansionOutcome::ExpandError(msg, errors)) => {
+                    message_expansion_error(&path, msg, errors);
+                }
+                Ok(ExpansionOutcome::ParseError(error, code)) => {
+                    message_parse_error(&path, error, code);
+                }
+                Ok(_) => unreachable!("the expansion failed"),
+                Err(e) => eprintln!("Error: {:#?}", e),
+            }
+            failures += 1;
+        } else if run.format == OutputFormat::Human {
+            let (changed, failed) = feature_changes(&test_records);
+            message_feature_changes(&path, &changed, &failed);
+        }
+        records.extend(test_records);
+    }
+
+    let failed = records.iter().filter(|record| record.failed()).count();
+    run.finish(&project, &records, failed, 0);
+
+    if failures > 0 {
+        eprintln!("\n\n");
+        panic!("{} of {} tests failed", failures, run.tests.len());
+    }
+}
+
+/// Compares the expansion with a combination of features to the one with the default features.
+fn compare_to_baseline(
+    comparison: Comparison,
+    baseline: &str,
+    expansion: Expansion,
+) -> ExpansionOutcome {
+    match expansion {
+        Expansion::Code(code) if compare::same(comparison, baseline, &code) => {
+            ExpansionOutcome::Same
+        }
+        Expansion::Code(code) => {
+            ExpansionOutcome::ChangedByFeatures(baseline.as_bytes().to_vec(), code.into_bytes())
+        }
+        Expansion::Failed(outcome) => outcome,
+    }
+}
+
+/// Returns the combinations of features that change the expansion of a test file,
+/// and the ones the expansion fails with.
+fn feature_changes(records: &[TestRecord]) -> (Vec<&str>, Vec<&str>) {
+    let mut changed = Vec::new();
+    let mut failed = Vec::new();
+    for record in records {
+        let combination = match &record.feature_set {
+            Some(combination) => combination.as_str(),
+            None => continue,
+        };
+        match &record.result {
+            Ok(ExpansionOutcome::Same) => {}
+            Ok(ExpansionOutcome::ChangedByFeatures(..)) => changed.push(combination),
+            _ => failed.push(combination),
+        }
+    }
+    (changed, failed)
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -111,53 +373,289 @@ enum ExpansionBehavior {
     ExpectFiles,
 }
 
-fn run_tests<I, S>(path: impl AsRef<Path>, expansion_behavior: ExpansionBehavior, args: Option<I>)
-where
-    I: IntoIterator<Item = S> + Clone,
-    S: AsRef<OsStr>,
-{
-    let tests = expand_globs(&path)
+/// Describes how `.expanded.rs` files are written, set with the `MACROTEST` environment variable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Mode {
+    /// Missing files are created, unless they're expected to exist.
+    Default,
+    /// Files with a different expansion are overwritten, orphaned files are removed.
+    Overwrite,
+    /// Files are expected to exist and nothing is ever written. The default in CI.
+    Check,
+}
+
+/// Returns `true` if the tests run in CI, as indicated by the `CI` environment variable.
+fn is_ci() -> bool {
+    env::var_os("CI").is_some_and(|v| !v.is_empty() && v != "false" && v != "0")
+}
+
+/// Arguments the tests are expanded with.
+#[derive(Debug, Default)]
+struct Variant {
+    /// The name of the feature set, which is a part of the `.expanded.rs` file name.
+    name: Option<String>,
+    args: Option<Vec<OsString>>,
+    /// Whether the arguments select the features, otherwise the features enabled for
+    /// the test binary are passed on.
+    selects_features: bool,
+}
+
+impl Variant {
+    fn with_args<I, S>(args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_owned())
+            .collect::<Vec<_>>();
+        Variant {
+            name: None,
+            selects_features: features::selected_by(&args),
+            args: Some(args),
+        }
+    }
+
+    fn feature_sets(feature_sets: &[(&str, &[&str])]) -> Vec<Self> {
+        feature_sets
+            .iter()
+            .map(|(name, features)| {
+                let valid = !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+                if !valid {
+                    panic!("invalid feature set name: {:?}", name);
+                }
+
+                Variant {
+                    name: Some((*name).to_owned()),
+                    args: Some(vec![
+                        OsString::from("--no-default-features"),
+                        OsString::from("--features"),
+                        OsString::from(features.join(",")),
+                    ]),
+                    selects_features: true,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The test files matching a glob pattern, selected with `MACROTEST_FILTER`.
+struct Run {
+    /// The glob pattern, which names the test suite in the events and the reports.
+    suite: String,
+    tests: Vec<ExpandedTest>,
+    /// The `.expanded.rs` files matching the glob pattern.
+    snapshots: Vec<ExpandedTest>,
+    /// The number of test files that were filtered out.
+    filtered_out: usize,
+    format: OutputFormat,
+    start: Instant,
+}
+
+impl Run {
+    /// Collects the test files and reads the settings of the run from the environment.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `MACROTEST_FILTER` or `MACROTEST_FORMAT` has an unrecognized value.
+    fn new(path: &Path) -> Self {
+        let (snapshots, tests): (Vec<_>, Vec<_>) = expand_globs(path)
+            .into_iter()
+            .partition(|t| t.test.to_string_lossy().ends_with(EXPANDED_RS_SUFFIX));
+
+        let filter = Filter::from_env().unwrap_or_else(|err| panic!("{}", err));
+        let total = tests.len();
+        let tests = filter.apply(tests, |t| &t.test);
+        let suite = path.display().to_string();
+        if tests.is_empty() && total > 0 {
+            message_all_filtered_out(&suite, total);
+        }
+
+        let format = OutputFormat::from_env().unwrap_or_else(|err| panic!("{}", err));
+
+        Run {
+            suite,
+            filtered_out: total - tests.len(),
+            tests,
+            snapshots,
+            format,
+            start: Instant::now(),
+        }
+    }
+
+    /// Announces `len` tests, each test file being expanded once per variant.
+    fn start(&self, len: usize, filtered_out: usize) {
+        if self.format == OutputFormat::Human {
+            if filtered_out > 0 {
+                println!(
+                    "Running {} macro expansion tests, {} filtered out",
+                    len, filtered_out
+                );
+            } else {
+                println!("Running {} macro expansion tests", len);
+            }
+        }
+        Event::RunStarted {
+            suite: &self.suite,
+            tests: len,
+            filtered_out,
+        }
+        .emit(self.format);
+    }
+
+    fn prepare(&self) -> Project {
+        prepare(&self.tests, self.format).unwrap_or_else(|err| {
+            panic!("prepare failed: {:#?}", err);
+        })
+    }
+
+    fn build(&self, project: &Project) {
+        Event::BuildStarted { suite: &self.suite }.emit(self.format);
+        let build_start = Instant::now();
+        let build = cargo::build_dependencies(project);
+        Event::BuildFinished {
+            suite: &self.suite,
+            success: build.is_ok(),
+            duration: build_start.elapsed().as_secs_f64(),
+        }
+        .emit(self.format);
+        build.unwrap_or_else(|err| {
+            panic!("prepare failed: {:#?}", err);
+        });
+    }
+
+    /// Reports the end of the run and writes the HTML and JUnit reports.
+    fn finish(&self, project: &Project, records: &[TestRecord], failed: usize, refreshed: usize) {
+        Event::RunFinished {
+            suite: &self.suite,
+            passed: records.len() - failed - refreshed,
+            failed,
+            refreshed,
+            duration: self.start.elapsed().as_secs_f64(),
+        }
+        .emit(self.format);
+
+        if let Err(e) = html::write(
+            &project.html_report,
+            &self.suite,
+            records,
+            project.diff_context,
+        ) {
+            eprintln!(
+                "Failed to write the HTML report `{}`: {}",
+                project.html_report.display(),
+                e
+            );
+        }
+
+        if let Some(junit) = &project.junit {
+            if let Err(e) = junit::write(
+                junit,
+                &self.suite,
+                &project.crate_name,
+                records,
+                project.diff_context,
+            ) {
+                eprintln!(
+                    "Failed to write the JUnit report `{}`: {}",
+                    junit.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+fn run_tests(
+    path: impl AsRef<Path>,
+    expansion_behavior: ExpansionBehavior,
+    variants: Vec<Variant>,
+) {
+    let run = Run::new(path.as_ref());
+    let len = run.tests.len() * variants.len();
+    run.start(len, run.filtered_out * variants.len());
+
+    let project = run.prepare();
+    let store = Store::new(project.store, path.as_ref(), project.pending_dir.clone());
+
+    // Snapshots left behind after their test files were renamed or deleted.
+    let orphans = store
+        .snapshots(run.snapshots.iter().map(|t| t.test.clone()).collect())
+        .unwrap_or_else(|err| {
+            panic!("prepare failed: {:#?}", err);
+        })
         .into_iter()
-        .filter(|t| !t.test.to_string_lossy().ends_with(EXPANDED_RS_SUFFIX))
+        .filter(|snapshot| is_orphan(snapshot, &variants))
         .collect::<Vec<_>>();
 
-    let len = tests.len();
-    println!("Running {} macro expansion tests", len);
-
-    let project = prepare(&tests).unwrap_or_else(|err| {
-        panic!("prepare failed: {:#?}", err);
-    });
+    run.build(&project);
 
     let mut failures = 0;
-    for test in tests {
-        let path = test.test.display();
-        let expanded_path = test.test.with_extension(EXPANDED_RS_SUFFIX);
+    let mut refreshed = 0;
+    let mut records = Vec::with_capacity(len);
+    for (test, variant) in run
+        .tests
+        .iter()
+        .flat_map(|test| variants.iter().map(move |variant| (test, variant)))
+    {
+        let path = test_name(&test.test, variant.name.as_deref());
+        let expanded_path = test.expanded_path(variant);
+
+        let start = Instant::now();
+        let result = test.run(&project, &store, expansion_behavior, variant);
+        let duration = start.elapsed();
 
-        match test.run(&project, expansion_behavior, &args) {
+        match &result {
             Ok(outcome) => match outcome {
                 ExpansionOutcome::Same => {
-                    let _ = writeln!(std::io::stdout(), "{} - ok", path);
+                    if run.format == OutputFormat::Human {
+                        let _ = writeln!(std::io::stdout(), "{} - ok", path);
+                    }
                 }
 
                 ExpansionOutcome::Different(a, b) => {
-                    message_different(&path.to_string(), &a, &b);
+                    message_different(&path, a, b, project.diff_context);
+                    if project.mode != Mode::Check {
+                        message_pending(&store.pending_path(&expanded_path));
+                    }
                     failures += 1;
                 }
 
                 ExpansionOutcome::Update => {
-                    let _ = writeln!(std::io::stderr(), "{} - refreshed", expanded_path.display());
+                    let _ = writeln!(
+                        std::io::stderr(),
+                        "{} - refreshed",
+                        store.location(&expanded_path)
+                    );
+                    refreshed += 1;
                 }
 
-                ExpansionOutcome::ExpandError(msg) => {
-                    message_expansion_error(msg);
+                ExpansionOutcome::ExpandError(msg, errors) => {
+                    message_expansion_error(&path, msg, errors);
+                    failures += 1;
+                }
+                ExpansionOutcome::ParseError(error, code) => {
+                    message_parse_error(&path, error, code);
                     failures += 1;
                 }
+                ExpansionOutcome::ChangedByFeatures(..) => {
+                    unreachable!("only reported by expand_feature_powerset")
+                }
                 ExpansionOutcome::NoExpandedFileFound => {
                     let _ = writeln!(
                         std::io::stderr(),
                         "{} is expected but not found",
-                        expanded_path.display()
+                        store.location(&expanded_path)
                     );
+                    if project.mode == Mode::Check {
+                        message_check_mode();
+                    } else {
+                        message_pending(&store.pending_path(&expanded_path));
+                    }
                     failures += 1;
                 }
             },
@@ -167,16 +665,106 @@ where
                 failures += 1;
             }
         }
+
+        let record = TestRecord {
+            test: test.test.clone(),
+            feature_set: variant.name.clone(),
+            expanded: expanded_path,
+            duration,
+            result,
+        };
+        events::test_event(&run.suite, &record, project.diff_context).emit(run.format);
+        records.push(record);
     }
 
+    let mut orphan_failures = 0;
+    for orphan in &orphans {
+        let removed = project.mode == Mode::Overwrite;
+        if removed {
+            if let Err(e) = store.remove(orphan) {
+                eprintln!("Failed to remove `{}`: {}", orphan.display(), e);
+                continue;
+            }
+        }
+
+        message_orphan(
+            &store.location(orphan),
+            &test_path(orphan, &variants),
+            removed,
+        );
+        if !removed && project.fail_on_orphans {
+            orphan_failures += 1;
+        }
+        Event::Orphan {
+            suite: &run.suite,
+            path: orphan,
+            removed,
+        }
+        .emit(run.format);
+    }
+
+    run.finish(&project, &records, failures, refreshed);
+
     if failures > 0 {
         eprintln!("\n\n");
         panic!("{} of {} tests failed", failures, len);
     }
+
+    if orphan_failures > 0 {
+        eprintln!("\n\n");
+        panic!("{} orphaned .expanded.rs files found", orphan_failures);
+    }
+}
+
+/// Returns the path of the test file the `.expanded.rs` file belongs to,
+/// stripping the name of the feature set, if any.
+fn test_path(expanded: &Path, variants: &[Variant]) -> PathBuf {
+    let file_name = expanded
+        .file_name()
+        .expect("no file name")
+        .to_string_lossy();
+    let mut stem = file_name
+        .strip_suffix(EXPANDED_RS_SUFFIX)
+        .unwrap_or(&file_name);
+    for name in variants.iter().filter_map(|v| v.name.as_deref()) {
+        if let Some(stripped) = stem
+            .strip_suffix('.')
+            .and_then(|s| s.strip_suffix(name))
+            .and_then(|s| s.strip_suffix('.'))
+        {
+            stem = &stem[..stripped.len() + 1];
+            break;
+        }
+    }
+    expanded.with_file_name(format!("{}rs", stem))
+}
+
+/// Returns `true` if the test file the `.expanded.rs` file belongs to doesn't exist.
+///
+/// In plain [`expand`] runs, a `<stem>.<name>.expanded.rs` file next to `<stem>.rs` is taken for
+/// the snapshot of a feature set, since the same test files may also be expanded with
+/// [`expand_feature_sets`]. When the feature sets are named, a snapshot of a set that is no
+/// longer listed is an orphan.
+fn is_orphan(expanded: &Path, variants: &[Variant]) -> bool {
+    let test = test_path(expanded, variants);
+    if test.exists() {
+        return false;
+    }
+    if variants.iter().any(|variant| variant.name.is_some()) {
+        return true;
+    }
+    let stem = test.file_stem().expect("no file name").to_string_lossy();
+    match stem.rsplit_once('.') {
+        Some((stem, _feature_set)) => !expanded.with_file_name(format!("{}.rs", stem)).exists(),
+        None => true,
+    }
 }
 
-fn prepare(tests: &[ExpandedTest]) -> Result<Project> {
-    let metadata = cargo::metadata()?;
+fn prepare(tests: &[ExpandedTest], format: OutputFormat) -> Result<Project> {
+    cargo::preflight()?;
+
+    let resolution = Resolution::from_env()?;
+    let metadata = cargo::metadata(resolution)?;
     let target_dir = metadata.target_directory;
     let workspace = metadata.workspace_root;
 
@@ -186,14 +774,64 @@ fn prepare(tests: &[ExpandedTest]) -> Result<Project> {
         .map(PathBuf::from)
         .ok_or(Error::ManifestDir)?;
 
+    // Cargo reports canonical paths, while the manifest directory may go through a symlink.
+    let canonicalize = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let source_manifest = canonicalize(&path!(source_dir / "Cargo.toml"));
+    let package = metadata
+        .packages
+        .into_iter()
+        .find(|package| canonicalize(&package.manifest_path) == source_manifest)
+        .ok_or(Error::PackageMetadata)?;
+
     let features = features::find();
 
-    let overwrite = match env::var_os("MACROTEST") {
-        Some(ref v) if v == "overwrite" => true,
-        Some(v) => return Err(Error::UnrecognizedEnv(v)),
+    let mode = match env::var_os("MACROTEST") {
+        Some(ref v) if v == "overwrite" => Mode::Overwrite,
+        Some(ref v) if v == "check" => Mode::Check,
+        Some(v) => return Err(Error::UnrecognizedEnv("MACROTEST", v)),
+        None if is_ci() => Mode::Check,
+        None => Mode::Default,
+    };
+
+    let comparison = Comparison::from_env()?;
+
+    let formatter = Formatter::from_env()?;
+    format::check(formatter)?;
+
+    let fail_on_parse_error = match env::var_os("MACROTEST_PARSE_ERROR") {
+        Some(ref v) if v == "warn" => false,
+        Some(ref v) if v == "fail" => true,
+        Some(v) => return Err(Error::UnrecognizedEnv("MACROTEST_PARSE_ERROR", v)),
+        None => false,
+    };
+
+    let fail_on_orphans = match env::var_os("MACROTEST_ORPHANS") {
+        Some(ref v) if v == "warn" => false,
+        Some(ref v) if v == "fail" => true,
+        Some(v) => return Err(Error::UnrecognizedEnv("MACROTEST_ORPHANS", v)),
         None => false,
     };
 
+    let diff_context = match env::var_os("MACROTEST_DIFF_CONTEXT") {
+        Some(v) => match v.to_str().and_then(|s| s.parse().ok()) {
+            Some(context) => context,
+            None => return Err(Error::UnrecognizedEnv("MACROTEST_DIFF_CONTEXT", v)),
+        },
+        None => 3,
+    };
+
+    let junit = env::var_os("MACROTEST_JUNIT")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from);
+
+    let pending_dir = env::var_os("MACROTEST_PENDING")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from);
+
+    let store = StoreKind::from_env()?;
+
+    let rustflags = rustflags::resolve(&source_dir);
+
     // Use random string for the crate dir to
     // prevent conflicts when running parallel tests.
     let random_string: String = iter::repeat_with(fastrand::alphanumeric).take(42).collect();
@@ -206,25 +844,49 @@ fn prepare(tests: &[ExpandedTest]) -> Result<Project> {
     }
 
     let inner_target_dir = path!(target_dir / "tests" / "macrotest");
+    let html_report = path!(target_dir / "macrotest").join(format!(
+        "{}{}.html",
+        html::REPORT_PREFIX,
+        report::binary_name()
+    ));
 
     let mut project = Project {
         dir,
         source_dir,
         inner_target_dir,
         name: format!("{}-tests", crate_name),
+        crate_name: crate_name.clone(),
         features,
+        crate_features: package.features.keys().cloned().collect(),
+        edition: Some(package.edition.clone()),
         workspace,
-        overwrite,
+        mode,
+        comparison,
+        formatter,
+        fail_on_parse_error,
+        diff_context,
+        junit,
+        html_report,
+        fail_on_orphans,
+        pending_dir,
+        store,
+        resolution,
+        rustflags,
+        format,
     };
 
-    let manifest = make_manifest(crate_name, &project, tests)?;
+    let manifest = make_manifest(&project, &package, tests)?;
     let manifest_toml = toml::ser::to_string(&manifest)?;
 
-    let config = make_config();
+    let config = make_config(&project);
     let config_toml = toml::ser::to_string(&config)?;
 
-    if let Some(enabled_features) = &mut project.features {
-        enabled_features.retain(|feature| manifest.features.contains_key(feature));
+    match &mut project.features {
+        Some(features) => features
+            .enabled
+            .retain(|feature| manifest.features.contains_key(feature)),
+        None if !manifest.features.is_empty() => message_features_not_detected(),
+        None => {}
     }
 
     fs::create_dir_all(path!(project.dir / ".cargo"))?;
@@ -238,27 +900,46 @@ fn prepare(tests: &[ExpandedTest]) -> Result<Project> {
         otherwise => otherwise,
     }?;
 
-    fs::create_dir_all(&project.inner_target_dir)?;
+    let dependencies = manifest
+        .dependencies
+        .iter()
+        .chain(
+            manifest
+                .target
+                .values()
+                .flat_map(|target| &target.dependencies),
+        )
+        .map(|(name, dependency)| match dependency.rest.get("package") {
+            Some(serde_json::Value::String(package)) => package.clone(),
+            _ => name.clone(),
+        })
+        .collect::<Vec<_>>();
+    cargo::resolve(&project, &dependencies)?;
 
-    cargo::build_dependencies(&project)?;
+    fs::create_dir_all(&project.inner_target_dir)?;
 
     Ok(project)
 }
 
 fn make_manifest(
-    crate_name: String,
     project: &Project,
+    package: &PackageMetadata,
     tests: &[ExpandedTest],
 ) -> Result<Manifest> {
     let source_manifest = dependencies::get_manifest(&project.source_dir);
     let workspace_manifest = dependencies::get_workspace_manifest(&project.workspace);
 
-    let features = source_manifest
+    // The tests can only use the crate if it has a library target.
+    let has_lib = package.lib_name().is_some();
+
+    let features = package
         .features
         .iter()
         .map(|(feature, source_deps)| {
-            let enable = format!("{}/{}", crate_name, feature);
-            let mut deps = vec![enable];
+            let mut deps = Vec::new();
+            if has_lib {
+                deps.push(format!("{}/{}", package.name, feature));
+            }
             deps.extend(
                 source_deps
                     .iter()
@@ -270,43 +951,58 @@ fn make_manifest(
         .collect();
 
     let mut manifest = Manifest {
-        cargo_features: source_manifest.cargo_features.clone(),
+        cargo_features: source_manifest.cargo_features,
         package: Package {
             name: project.name.clone(),
             version: "0.0.0".to_owned(),
-            edition: source_manifest.package.edition,
+            edition: package.edition.clone(),
             publish: false,
         },
         features,
         dependencies: std::collections::BTreeMap::new(),
+        target: std::collections::BTreeMap::new(),
         bins: Vec::new(),
-        workspace: Some(Workspace {
-            package: crate::manifest::WorkspacePackage {
-                edition: workspace_manifest.workspace.package.edition,
-            },
-            dependencies: workspace_manifest.workspace.dependencies,
-        }),
+        workspace: Some(Workspace {}),
         // Within a workspace, only the [patch] and [replace] sections in
         // the workspace root's Cargo.toml are applied by Cargo.
         patch: workspace_manifest.patch,
         replace: workspace_manifest.replace,
     };
 
-    manifest.dependencies.extend(source_manifest.dependencies);
-    manifest
+    // Normal and dev-dependencies are available to the tests. Dev-dependencies come last,
+    // so they take precedence, as in the source manifest.
+    let mut deps = package
         .dependencies
-        .extend(source_manifest.dev_dependencies);
-    manifest.dependencies.insert(
-        crate_name,
-        Dependency {
-            version: None,
-            path: Some(project.source_dir.clone()),
-            default_features: false,
-            features: Vec::new(),
-            workspace: false,
-            rest: std::collections::BTreeMap::new(),
-        },
-    );
+        .iter()
+        .filter(|dep| dep.name != "macrotest")
+        .filter(|dep| dep.kind.as_deref() != Some("build"))
+        .collect::<Vec<_>>();
+    deps.sort_by_key(|dep| dep.kind.is_some());
+    for dep in deps {
+        let (name, dependency) = dependencies::from_metadata(dep);
+        let dependencies = match &dep.target {
+            Some(cfg) => &mut manifest.target.entry(cfg.clone()).or_default().dependencies,
+            None => &mut manifest.dependencies,
+        };
+        dependencies.insert(name, dependency);
+    }
+
+    let mut tables = vec![&manifest.dependencies];
+    tables.extend(manifest.target.values().map(|target| &target.dependencies));
+    dependencies::retain_optional(&mut manifest.features, &tables);
+
+    if has_lib {
+        manifest.dependencies.insert(
+            package.name.clone(),
+            Dependency {
+                version: None,
+                path: Some(project.source_dir.clone()),
+                default_features: false,
+                features: Vec::new(),
+                rest: std::collections::BTreeMap::new(),
+            },
+        );
+    }
 
     manifest.bins.push(Bin {
         name: Name(project.name.to_owned()),
@@ -325,21 +1021,78 @@ fn make_manifest(
     Ok(manifest)
 }
 
-fn make_config() -> Config {
+fn make_config(project: &Project) -> Config {
+    let mut source = config::get_source_config(&project.source_dir);
+
+    // The resolved rustflags already include the ones of the matching targets.
+    if let Some(toml::Value::Table(targets)) = source.get_mut("target") {
+        for (_, target) in targets.iter_mut() {
+            if let toml::Value::Table(target) = target {
+                target.remove("rustflags");
+            }
+        }
+    }
+
     Config {
         build: Build {
-            rustflags: rustflags::make_vec(),
+            rustflags: project.rustflags.clone(),
         },
+        source,
     }
 }
 
 #[derive(Debug)]
-enum ExpansionOutcome {
+pub(crate) enum ExpansionOutcome {
     Same,
     Different(Vec<u8>, Vec<u8>),
     Update,
-    ExpandError(Vec<u8>),
+    /// `cargo expand` failed, contains its `STDERR` and the compiler errors, if any.
+    ExpandError(Vec<u8>, Vec<String>),
+    ParseError(syn::Error, String),
     NoExpandedFileFound,
+    /// The expansion with a combination of features differs from the one with the default
+    /// features, contains both. Reported by `expand_feature_powerset`, this is not a failure.
+    ChangedByFeatures(Vec<u8>, Vec<u8>),
+}
+
+/// The result of expanding a test file.
+enum Expansion {
+    /// The normalized expanded code.
+    Code(String),
+    /// The expansion failed, contains the outcome of the test.
+    Failed(ExpansionOutcome),
+}
+
+/// The result of a single expansion test, used to build reports.
+pub(crate) struct TestRecord {
+    pub test: PathBuf,
+    pub feature_set: Option<String>,
+    pub expanded: PathBuf,
+    pub duration: Duration,
+    pub result: Result<ExpansionOutcome>,
+}
+
+impl TestRecord {
+    /// The test file path followed by the name of the feature set, if any.
+    pub fn name(&self) -> String {
+        test_name(&self.test, self.feature_set.as_deref())
+    }
+
+    pub fn failed(&self) -> bool {
+        !matches!(
+            self.result,
+            Ok(ExpansionOutcome::Same)
+                | Ok(ExpansionOutcome::Update)
+                | Ok(ExpansionOutcome::ChangedByFeatures(..))
+        )
+    }
+}
+
+fn test_name(test: &Path, feature_set: Option<&str>) -> String {
+    match feature_set {
+        Some(name) => format!("{} [{}]", test.display(), name),
+        None => test.display().to_string(),
+    }
 }
 
 struct ExpandedTest {
@@ -349,75 +1102,154 @@ struct ExpandedTest {
 }
 
 impl ExpandedTest {
-    pub fn run<I, S>(
-        &self,
-        project: &Project,
-        expansion_behavior: ExpansionBehavior,
-        args: &Option<I>,
-    ) -> Result<ExpansionOutcome>
-    where
-        I: IntoIterator<Item = S> + Clone,
-        S: AsRef<OsStr>,
-    {
-        let (success, output_bytes) = cargo::expand(project, &self.name, args)?;
-
-        if !success {
-            return Ok(ExpansionOutcome::ExpandError(output_bytes));
-        }
-
+    /// Returns the path of the `.expanded.rs` file for the given feature set.
+    fn expanded_path(&self, variant: &Variant) -> PathBuf {
         let file_stem = self
             .test
             .file_stem()
             .expect("no file stem")
-            .to_string_lossy()
-            .into_owned();
-        let mut expanded = self.test.clone();
-        expanded.pop();
-        let expanded = &expanded.join(format!("{}.{}", file_stem, EXPANDED_RS_SUFFIX));
+            .to_string_lossy();
+        let file_name = match &variant.name {
+            Some(name) => format!("{}.{}.{}", file_stem, name, EXPANDED_RS_SUFFIX),
+            None => format!("{}.{}", file_stem, EXPANDED_RS_SUFFIX),
+        };
+        self.test.with_file_name(file_name)
+    }
+
+    /// Expands the test and normalizes the result.
+    ///
+    /// Returns the outcome of the test instead if the expansion failed.
+    fn expand(&self, project: &Project, variant: &Variant) -> Result<Expansion> {
+        let mut args = variant.args.clone().unwrap_or_default();
+        if !variant.selects_features {
+            args.extend(project.features.iter().flat_map(Features::args));
+        }
+        let args = &Some(args);
+        let (success, output_bytes) = cargo::expand(project, &self.name, args)?;
+
+        if !success {
+            let errors = cargo::expansion_errors(project, &self.name, args)?
+                .into_iter()
+                .filter_map(|diagnostic| diagnostic.rendered)
+                .map(|rendered| self.fix_diagnostic(project, &rendered))
+                .collect();
+            return Ok(Expansion::Failed(ExpansionOutcome::ExpandError(
+                output_bytes,
+                errors,
+            )));
+        }
 
-        let output = normalize_expansion(&output_bytes);
+        let output = match normalize_expansion(&output_bytes, project.comparison) {
+            Ok(output) => format::format(project, output)?,
+            Err((error, code)) => {
+                if project.fail_on_parse_error {
+                    return Ok(Expansion::Failed(ExpansionOutcome::ParseError(error, code)));
+                }
 
-        if !expanded.exists() {
-            if let ExpansionBehavior::ExpectFiles = expansion_behavior {
-                return Ok(ExpansionOutcome::NoExpandedFileFound);
+                message_parse_warning(&self.test.display().to_string(), &error, &code);
+                code
             }
+        };
+        Ok(Expansion::Code(snapshot::normalize_text(&output)))
+    }
 
-            // Write a .expanded.rs file contents
-            std::fs::write(expanded, output)?;
+    pub fn run(
+        &self,
+        project: &Project,
+        store: &Store,
+        expansion_behavior: ExpansionBehavior,
+        variant: &Variant,
+    ) -> Result<ExpansionOutcome> {
+        let output = match self.expand(project, variant)? {
+            Expansion::Code(output) => output,
+            Expansion::Failed(outcome) => return Ok(outcome),
+        };
+
+        let expanded = &self.expanded_path(variant);
+
+        // Nothing is written in the check mode, regardless of the called function.
+        let (expansion_behavior, writable) = match project.mode {
+            Mode::Check => (ExpansionBehavior::ExpectFiles, false),
+            Mode::Default | Mode::Overwrite => (expansion_behavior, true),
+        };
+
+        let expected_expansion = match store.read(expanded)? {
+            Some(expected_expansion) => expected_expansion,
+            None => {
+                if let ExpansionBehavior::ExpectFiles = expansion_behavior {
+                    if writable {
+                        store.write_pending(expanded, &output)?;
+                    }
+                    return Ok(ExpansionOutcome::NoExpandedFileFound);
+                }
 
-            return Ok(ExpansionOutcome::Update);
-        }
+                // Write a .expanded.rs file contents
+                store.write(expanded, &output)?;
 
-        let expected_expansion_bytes = std::fs::read(expanded)?;
-        let expected_expansion = String::from_utf8_lossy(&expected_expansion_bytes);
+                return Ok(ExpansionOutcome::Update);
+            }
+        };
 
-        let same = output.lines().eq(expected_expansion.lines());
+        let same = compare::same(project.comparison, &expected_expansion, &output);
 
-        if !same && project.overwrite {
+        if !same && project.mode == Mode::Overwrite {
             if let ExpansionBehavior::ExpectFiles = expansion_behavior {
+                // The expected file is not overwritten, but the message points to the pending one.
+                store.write_pending(expanded, &output)?;
                 return Ok(ExpansionOutcome::NoExpandedFileFound);
             }
 
             // Write a .expanded.rs file contents
-            std::fs::write(expanded, output)?;
+            store.write(expanded, &output)?;
 
             return Ok(ExpansionOutcome::Update);
         }
 
         Ok(if same {
+            if writable {
+                store.remove_pending(expanded)?;
+            }
             ExpansionOutcome::Same
         } else {
-            let output_bytes = output.into_bytes(); // Use normalized text for a message
-            ExpansionOutcome::Different(expected_expansion_bytes, output_bytes)
+            if writable {
+                store.write_pending(expanded, &output)?;
+            }
+
+            // Use normalized text for a message
+            ExpansionOutcome::Different(expected_expansion.into_bytes(), output.into_bytes())
         })
     }
+
+    /// Points the compiler diagnostic at the test file instead of the temporary crate,
+    /// and replaces the name of the generated binary with the test file path.
+    fn fix_diagnostic(&self, project: &Project, rendered: &str) -> String {
+        let test = self.test.display().to_string();
+        let bin_path = project.source_dir.join(&self.test).display().to_string();
+        let source_dir = format!(
+            "{}{}",
+            project.source_dir.display(),
+            std::path::MAIN_SEPARATOR
+        );
+
+        rendered
+            .replace(&bin_path, &test)
+            .replace(&format!("`{}`", self.name.0), &format!("`{}`", test))
+            .replace(&source_dir, "")
+            .replace(&project.dir.display().to_string(), "<temporary crate>")
+    }
 }
 
-fn normalize_expansion(input: &[u8]) -> String {
+/// Strips the prelude injected by the compiler and pretty-prints the expanded code.
+///
+/// If the code can't be parsed, returns the parse error along with the raw code.
+fn normalize_expansion(
+    input: &[u8],
+    comparison: Comparison,
+) -> std::result::Result<String, (syn::Error, String)> {
     let code = String::from_utf8_lossy(input);
     let mut syntax_tree = match syn::parse_file(&code) {
         Ok(syntax_tree) => syntax_tree,
-        Err(_) => return code.into_owned(),
+        Err(error) => return Err((error, code.into_owned())),
     };
 
     // Strip the following:
@@ -467,7 +1299,10 @@ fn normalize_expansion(input: &[u8]) -> String {
         true
     });
 
-    prettyplease::unparse(&syntax_tree)
+    // Write snapshots with items in a canonical order so diffs stay meaningful.
+    comparison.canonicalize(&mut syntax_tree);
+
+    Ok(prettyplease::unparse(&syntax_tree))
 }
 
 fn expand_globs(path: impl AsRef<Path>) -> Vec<ExpandedTest> {
@@ -518,3 +1353,41 @@ fn expand_globs(path: impl AsRef<Path>) -> Vec<ExpandedTest> {
 
     vec
 }
+
+#[test]
+fn test_feature_changes() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-project/tests/expand_feature_sets");
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+    let baseline = read("feature_sets.default.expanded.rs");
+    let expansions = [
+        (
+            "test-feature",
+            read("feature_sets.test-feature.expanded.rs"),
+        ),
+        ("other", baseline.clone()),
+    ];
+
+    let records = expansions
+        .into_iter()
+        .map(|(combination, code)| TestRecord {
+            test: PathBuf::from("tests/expand_feature_sets/feature_sets.rs"),
+            feature_set: Some(combination.to_owned()),
+            expanded: PathBuf::new(),
+            duration: Duration::ZERO,
+            result: Ok(compare_to_baseline(
+                Comparison::Text,
+                &baseline,
+                Expansion::Code(code),
+            )),
+        })
+        .collect::<Vec<_>>();
+
+    let (changed, failed) = feature_changes(&records);
+    assert_eq!(changed, ["test-feature"]);
+    assert!(failed.is_empty());
+    assert_eq!(
+        records[0].name(),
+        "tests/expand_feature_sets/feature_sets.rs [test-feature]"
+    );
+    assert!(!records[0].failed());
+}
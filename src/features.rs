@@ -1,13 +1,97 @@
 use serde::de::{self, Deserialize, DeserializeOwned, Deserializer};
 use serde_derive::Deserialize;
+use std::cell::RefCell;
 use std::env;
 use std::error::Error;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::path::PathBuf;
 
-pub fn find() -> Option<Vec<String>> {
-    try_find().ok()
+/// Features of the crate enabled for the running test binary.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Features {
+    pub enabled: Vec<String>,
+    /// Whether the default features are enabled.
+    pub default_features: bool,
+}
+
+impl Features {
+    /// Returns the `cargo` arguments enabling the same features.
+    pub(crate) fn args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        if !self.default_features {
+            args.push(OsString::from("--no-default-features"));
+        }
+        let enabled = self
+            .enabled
+            .iter()
+            .filter(|feature| *feature != "default")
+            .cloned()
+            .collect::<Vec<_>>();
+        if !enabled.is_empty() {
+            args.push(OsString::from("--features"));
+            args.push(OsString::from(enabled.join(",")));
+        }
+        args
+    }
+}
+
+thread_local! {
+    /// Features passed with the `features!` macro, which take precedence over the detected ones
+    /// for the next expansion on the same thread.
+    static DECLARED: RefCell<Option<Features>> = const { RefCell::new(None) };
+}
+
+/// Records which of the features listed in the `features!` macro are enabled.
+#[doc(hidden)]
+pub fn __set_features(features: &[(&str, bool)]) {
+    let enabled = features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| (*feature).to_owned())
+        .collect::<Vec<_>>();
+
+    // Unless `default` is listed, it's not known whether it's disabled.
+    let default_features = features
+        .iter()
+        .all(|(feature, enabled)| *feature != "default" || *enabled);
+
+    DECLARED.with(|declared| {
+        *declared.borrow_mut() = Some(Features {
+            enabled,
+            default_features,
+        });
+    });
+}
+
+/// Returns the features enabled for the running test binary: the ones declared with
+/// the `features!` macro on this thread since the previous expansion or, if it wasn't called,
+/// the ones found in the build fingerprint.
+///
+/// The declaration is consumed, so that it doesn't leak into the expansions of other tests,
+/// even if they run on the same thread, e.g. with `--test-threads=1`.
+pub(crate) fn find() -> Option<Features> {
+    if let Some(features) = DECLARED.with(|declared| declared.borrow_mut().take()) {
+        return Some(features);
+    }
+
+    try_find().ok().map(|enabled| Features {
+        default_features: enabled.iter().any(|feature| feature == "default"),
+        enabled,
+    })
+}
+
+/// Returns `true` if the `cargo` arguments select the features explicitly,
+/// in which case the features of the test binary are not passed on.
+pub(crate) fn selected_by<S: AsRef<OsStr>>(args: &[S]) -> bool {
+    args.iter().any(|arg| {
+        let arg = arg.as_ref().to_string_lossy();
+        arg == "--all-features"
+            || arg == "--no-default-features"
+            || arg == "--features"
+            || arg.starts_with("--features=")
+            || arg.starts_with("-F")
+    })
 }
 
 struct Ignored;
@@ -29,17 +113,16 @@ fn try_find() -> Result<Vec<String>, Ignored> {
     //   /path/to/crate_name/target/debug/deps/test_name-HASH
     let test_binary = env::args_os().next().ok_or(Ignored)?;
 
+    let binary_path = PathBuf::from(&test_binary);
+
     // The hash at the end is ascii so not lossy, rest of conversion doesn't
-    // matter.
-    let test_binary_lossy = test_binary.to_string_lossy();
-    let hash = test_binary_lossy
-        .get(test_binary_lossy.len() - 17..)
-        .ok_or(Ignored)?;
-    if !hash.starts_with('-') || !hash[1..].bytes().all(is_lower_hex_digit) {
+    // matter. The file stem is used to skip the `.exe` extension on Windows.
+    let stem = binary_path.file_stem().ok_or(Ignored)?.to_string_lossy();
+    let (_, hash) = stem.rsplit_once('-').ok_or(Ignored)?;
+    if hash.is_empty() || !hash.bytes().all(is_lower_hex_digit) {
         return Err(Ignored);
     }
-
-    let binary_path = PathBuf::from(&test_binary);
+    let hash = format!("-{}", hash);
 
     // Feature selection is saved in:
     //   /path/to/crate_name/target/debug/.fingerprint/*-HASH/*-HASH.json
@@ -57,7 +140,7 @@ fn try_find() -> Result<Vec<String>, Ignored> {
     for entry in fingerprint_dir.read_dir()? {
         let entry = entry?;
         let is_dir = entry.file_type()?.is_dir();
-        let matching_hash = entry.file_name().to_string_lossy().ends_with(hash);
+        let matching_hash = entry.file_name().to_string_lossy().ends_with(&hash);
         if is_dir && matching_hash {
             hash_matches.push(entry.path());
         }
@@ -98,3 +181,30 @@ where
     let json = String::deserialize(deserializer)?;
     serde_json::from_str(&json).map_err(de::Error::custom)
 }
+
+#[test]
+fn test_declared_features() {
+    let detected = find();
+
+    __set_features(&[("default", true), ("a", false), ("b", true)]);
+    let declared = Features {
+        enabled: vec!["default".to_owned(), "b".to_owned()],
+        default_features: true,
+    };
+    assert_eq!(find(), Some(declared.clone()));
+    assert_eq!(find(), detected);
+
+    assert_eq!(declared.args(), ["--features", "b"]);
+    __set_features(&[("default", false), ("b", true)]);
+    assert_eq!(
+        find().unwrap().args(),
+        ["--no-default-features", "--features", "b"]
+    );
+    __set_features(&[("b", true)]);
+    assert_eq!(find().unwrap().args(), ["--features", "b"]);
+
+    assert!(selected_by(&["-F", "b"]));
+    assert!(selected_by(&["-Fb"]));
+    assert!(selected_by(&["--features=b"]));
+    assert!(!selected_by(&["--release", "--lib"]));
+}
@@ -0,0 +1,145 @@
+use std::env;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+const MACROTEST_FILTER: &str = "MACROTEST_FILTER";
+
+/// libtest options that take a value, which must not be mistaken for filters.
+const OPTIONS_WITH_VALUES: [&str; 7] = [
+    "--color",
+    "--format",
+    "--logfile",
+    "--shuffle-seed",
+    "--skip",
+    "--test-threads",
+    "-Z",
+];
+
+/// Selects the expansion tests to run by their paths.
+#[derive(Debug, Default)]
+pub(crate) struct Filter {
+    /// The pattern from the `MACROTEST_FILTER` environment variable.
+    pattern: Option<String>,
+    /// Filters passed to the test binary, as in `cargo test -- <FILTER>`.
+    libtest: Vec<String>,
+    /// Patterns passed to the test binary with `--skip`.
+    skip: Vec<String>,
+}
+
+impl Filter {
+    /// Reads the filters from the `MACROTEST_FILTER` environment variable
+    /// and the arguments of the test binary.
+    pub(crate) fn from_env() -> Result<Self> {
+        let pattern = match env::var_os(MACROTEST_FILTER) {
+            Some(v) => match v.into_string() {
+                Ok(v) if v.is_empty() => None,
+                Ok(v) => Some(v),
+                Err(v) => return Err(Error::UnrecognizedEnv(MACROTEST_FILTER, v)),
+            },
+            None => None,
+        };
+
+        // The arguments that are not valid Unicode can't match any of the paths anyway.
+        let args = env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned());
+        let (libtest, skip) = parse_args(args);
+        Ok(Filter {
+            pattern,
+            libtest,
+            skip,
+        })
+    }
+
+    /// Keeps the tests whose paths match the filters, preserving their order.
+    ///
+    /// A test is selected if its path contains the `MACROTEST_FILTER` pattern, one of the libtest
+    /// filters and none of the `--skip` patterns. The libtest filters and `--skip` patterns select
+    /// the `#[test]` functions as well, so the filters are only applied if at least one of the
+    /// tests matches them, and the `--skip` patterns only if they don't exclude all the tests.
+    pub(crate) fn apply<T>(&self, tests: Vec<T>, path: impl Fn(&T) -> &Path) -> Vec<T> {
+        let contains = |test: &T, pattern: &str| path(test).to_string_lossy().contains(pattern);
+
+        let mut tests = tests
+            .into_iter()
+            .filter(|test| match &self.pattern {
+                Some(pattern) => contains(test, pattern),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        let matches_libtest = |test: &T| self.libtest.iter().any(|filter| contains(test, filter));
+        if tests.iter().any(matches_libtest) {
+            tests.retain(matches_libtest);
+        }
+
+        let not_skipped = |test: &T| !self.skip.iter().any(|pattern| contains(test, pattern));
+        if tests.iter().any(not_skipped) {
+            tests.retain(not_skipped);
+        }
+
+        tests
+    }
+}
+
+/// Splits the arguments of the test binary into filters and `--skip` patterns.
+fn parse_args(args: impl Iterator<Item = String>) -> (Vec<String>, Vec<String>) {
+    let mut filters = Vec::new();
+    let mut skip = Vec::new();
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        if let Some(pattern) = arg.strip_prefix("--skip=") {
+            skip.push(pattern.to_owned());
+        } else if arg == "--skip" {
+            skip.extend(args.next());
+        } else if OPTIONS_WITH_VALUES.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with('-') {
+            filters.push(arg);
+        }
+    }
+
+    (filters, skip)
+}
+
+#[test]
+fn test_filter() {
+    let args = [
+        "--test-threads",
+        "1",
+        "pass",
+        "--skip",
+        "third",
+        "--nocapture",
+        "first",
+    ];
+    let (libtest, skip) = parse_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(libtest, ["pass", "first"]);
+    assert_eq!(skip, ["third"]);
+
+    let tests = ["expand/first.rs", "expand/second.rs", "expand/third.rs"];
+    let select = |filter: &Filter| filter.apply(tests.to_vec(), |test| Path::new(test));
+
+    let filter = Filter {
+        libtest,
+        skip,
+        ..Filter::default()
+    };
+    assert_eq!(select(&filter), ["expand/first.rs"]);
+
+    let filter = Filter {
+        pattern: Some("second".to_owned()),
+        libtest: vec!["pass".to_owned()],
+        ..Filter::default()
+    };
+    assert_eq!(select(&filter), ["expand/second.rs"]);
+
+    // `cargo test -- --skip expand` skips the `#[test]` functions, not all the files.
+    let filter = Filter {
+        skip: vec!["expand".to_owned()],
+        ..Filter::default()
+    };
+    assert_eq!(select(&filter), tests);
+}
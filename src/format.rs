@@ -0,0 +1,96 @@
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use crate::error::{Error, Result};
+use crate::expand::Project;
+
+const MACROTEST_FORMATTER: &str = "MACROTEST_FORMATTER";
+
+/// Describes how the normalized expansion is formatted before it's compared and written.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Formatter {
+    /// Format with `prettyplease`, independently of the environment.
+    Prettyplease,
+    /// Format with `rustfmt`, respecting the `rustfmt.toml` of the crate under test.
+    Rustfmt,
+}
+
+impl Formatter {
+    /// Reads the formatter from the `MACROTEST_FORMATTER` environment variable.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var_os(MACROTEST_FORMATTER) {
+            Some(ref v) if v == "prettyplease" => Ok(Formatter::Prettyplease),
+            Some(ref v) if v == "rustfmt" => Ok(Formatter::Rustfmt),
+            Some(v) => Err(Error::UnrecognizedEnv(MACROTEST_FORMATTER, v)),
+            None => Ok(Formatter::Prettyplease),
+        }
+    }
+}
+
+fn rustfmt() -> Command {
+    Command::new(env::var_os("RUSTFMT").unwrap_or_else(|| "rustfmt".into()))
+}
+
+/// Checks that `rustfmt` can be executed if it's the selected formatter.
+pub(crate) fn check(formatter: Formatter) -> Result<()> {
+    if formatter != Formatter::Rustfmt {
+        return Ok(());
+    }
+
+    match rustfmt().arg("--version").output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(Error::Rustfmt(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::RustfmtNotFound),
+        Err(e) => Err(Error::Rustfmt(e.to_string())),
+    }
+}
+
+/// Formats the code that was already pretty-printed by `prettyplease`.
+pub(crate) fn format(project: &Project, code: String) -> Result<String> {
+    match project.formatter {
+        Formatter::Prettyplease => Ok(code),
+        Formatter::Rustfmt => rustfmt_stdin(project, code),
+    }
+}
+
+fn rustfmt_stdin(project: &Project, code: String) -> Result<String> {
+    let mut cmd = rustfmt();
+
+    // Formatting of the standard input picks up the configuration from the working directory.
+    cmd.current_dir(&project.source_dir)
+        .arg("--emit")
+        .arg("stdout")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(edition) = &project.edition {
+        cmd.arg("--edition").arg(edition);
+    }
+
+    let mut child = cmd.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::RustfmtNotFound,
+        _ => Error::Rustfmt(e.to_string()),
+    })?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| Error::Rustfmt("failed to open stdin".to_owned()))?;
+    let writer = std::thread::spawn(move || stdin.write_all(code.as_bytes()));
+    let output = child.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| Error::Rustfmt("failed to write to stdin".to_owned()))??;
+
+    if !output.status.success() {
+        return Err(Error::Rustfmt(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|_| Error::Rustfmt("output is not valid UTF-8".to_owned()))
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap as Map;
+use std::ops::Range;
+
+use diff::Result;
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+
+/// A token that fits on a single line. The range is in characters.
+#[derive(Debug)]
+struct Token {
+    columns: Range<usize>,
+    text: String,
+}
+
+/// Tokens of a source text grouped by line numbers (1-indexed).
+#[derive(Debug, Default)]
+pub(crate) struct LineTokens {
+    lines: Map<usize, Vec<Token>>,
+}
+
+impl LineTokens {
+    /// Tokenizes the text with the Rust tokenizer of `proc_macro2`.
+    ///
+    /// Single lines are often not balanced in terms of delimiters, so the text is tokenized
+    /// as a whole. If it can't be tokenized, no tokens are returned.
+    pub(crate) fn new(text: &str) -> Self {
+        let mut tokens = LineTokens::default();
+        if let Ok(stream) = text.parse::<TokenStream>() {
+            tokens.collect(stream);
+        }
+        tokens
+    }
+
+    fn collect(&mut self, stream: TokenStream) {
+        for tt in stream {
+            match tt {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(group.span_open(), open.to_owned());
+                    self.collect(group.stream());
+                    self.push(group.span_close(), close.to_owned());
+                }
+                tt => self.push(tt.span(), tt.to_string()),
+            }
+        }
+    }
+
+    fn push(&mut self, span: Span, text: String) {
+        let (start, end) = (span.start(), span.end());
+        // Tokens spanning multiple lines, such as string literals, are not highlighted.
+        if text.is_empty() || start.line != end.line {
+            return;
+        }
+
+        self.lines.entry(start.line).or_default().push(Token {
+            columns: start.column..end.column,
+            text,
+        });
+    }
+
+    fn line(&self, line: usize) -> &[Token] {
+        self.lines.get(&line).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Ranges of changed tokens on each of the changed lines, keyed by line numbers.
+#[derive(Debug, Default)]
+pub(crate) struct Highlights {
+    pub old: Map<usize, Vec<Range<usize>>>,
+    pub new: Map<usize, Vec<Range<usize>>>,
+}
+
+/// Finds the tokens that differ in the pairs of removed and added lines.
+///
+/// `changes` is a sequence of line changes starting at the given line numbers.
+/// A block of removed lines followed by a block of added lines is paired line by line.
+pub(crate) fn highlights(
+    changes: &[Result<&str>],
+    (mut old_line, mut new_line): (usize, usize),
+    old_tokens: &LineTokens,
+    new_tokens: &LineTokens,
+) -> Highlights {
+    let mut highlights = Highlights::default();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    for change in changes.iter().chain(Some(&Result::Both("", ""))) {
+        match change {
+            Result::Left(_) => {
+                removed.push(old_line);
+                old_line += 1;
+            }
+            Result::Right(_) => {
+                added.push(new_line);
+                new_line += 1;
+            }
+            Result::Both(..) => {
+                for (&old, &new) in removed.iter().zip(&added) {
+                    let (old_ranges, new_ranges) =
+                        changed_tokens(old_tokens.line(old), new_tokens.line(new));
+                    highlights.old.insert(old, old_ranges);
+                    highlights.new.insert(new, new_ranges);
+                }
+                removed.clear();
+                added.clear();
+                old_line += 1;
+                new_line += 1;
+            }
+        }
+    }
+
+    highlights
+}
+
+fn changed_tokens(old: &[Token], new: &[Token]) -> (Vec<Range<usize>>, Vec<Range<usize>>) {
+    let old_texts = old.iter().map(|t| t.text.as_str()).collect::<Vec<_>>();
+    let new_texts = new.iter().map(|t| t.text.as_str()).collect::<Vec<_>>();
+
+    let mut old_ranges = Vec::new();
+    let mut new_ranges = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for change in diff::slice(&old_texts, &new_texts) {
+        match change {
+            Result::Left(_) => {
+                old_ranges.push(old[i].columns.clone());
+                i += 1;
+            }
+            Result::Right(_) => {
+                new_ranges.push(new[j].columns.clone());
+                j += 1;
+            }
+            Result::Both(..) => {
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    (old_ranges, new_ranges)
+}
+
+/// Splits the line into segments, marking the ones that are covered by the character ranges.
+pub(crate) fn segments<'a>(line: &'a str, ranges: &[Range<usize>]) -> Vec<(&'a str, bool)> {
+    let mut segments: Vec<(&str, bool)> = Vec::new();
+    let mut start = 0;
+    let mut marked = false;
+
+    for (column, (offset, _)) in line.char_indices().enumerate() {
+        let is_marked = ranges.iter().any(|r| r.contains(&column));
+        if is_marked != marked {
+            if offset > start {
+                segments.push((&line[start..offset], marked));
+            }
+            start = offset;
+            marked = is_marked;
+        }
+    }
+    if start < line.len() {
+        segments.push((&line[start..], marked));
+    }
+
+    segments
+}
+
+#[test]
+fn test_highlights() {
+    let old = "impl<T: Clone> Foo for Bar<T> {\n    fn f() {}\n}\n";
+    let new = "impl<T: Copy> Foo for Bar<T> {\n    fn f() {}\n}\n";
+
+    let changes = diff::lines(old, new);
+    let highlights = highlights(
+        &changes,
+        (1, 1),
+        &LineTokens::new(old),
+        &LineTokens::new(new),
+    );
+
+    assert_eq!(highlights.old[&1], vec![Range { start: 8, end: 13 }]);
+    assert_eq!(highlights.new[&1], vec![Range { start: 8, end: 12 }]);
+    assert_eq!(
+        segments("impl<T: Copy> Foo", &highlights.new[&1]),
+        [("impl<T: ", false), ("Copy", true), ("> Foo", false)]
+    );
+}
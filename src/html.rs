@@ -0,0 +1,225 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::expand::{ExpansionOutcome, TestRecord};
+use crate::message::{hunks, line_changes};
+use crate::report::{escape, expansion_error, Suites};
+use crate::snapshot;
+use diff::Result as Change;
+
+static SUITES: Suites = Suites::new();
+
+/// The prefix of the file names of the reports, which are followed by the test binary name.
+pub(crate) const REPORT_PREFIX: &str = "report-";
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+h2 { font-size: 1.1em; margin-top: 2em; }
+table.tests { border-collapse: collapse; }
+table.tests td, table.tests th { padding: 0.2em 1em; text-align: left; }
+.ok { color: #1a7f37; }
+.refreshed { color: #0969da; }
+.changed { color: #9a6700; }
+.failed { color: #cf222e; font-weight: bold; }
+details { margin: 0.5em 0; }
+summary { cursor: pointer; font-family: monospace; }
+pre { background: #f6f8fa; padding: 0.5em; overflow-x: auto; }
+table.diff { border-collapse: collapse; font-family: monospace; font-size: 0.9em; width: 100%; }
+table.diff td { padding: 0 0.5em; white-space: pre; vertical-align: top; }
+table.diff td.no { color: #6e7781; text-align: right; user-select: none; }
+table.diff tr.hunk td { background: #ddf4ff; color: #57606a; }
+td.del { background: #ffebe9; }
+td.add { background: #e6ffec; }
+";
+
+/// Appends a test suite to the HTML report at `path` and rewrites the report, then updates
+/// the index linking the reports of all the test binaries in the same directory.
+pub(crate) fn write(
+    path: &Path,
+    suite: &str,
+    records: &[TestRecord],
+    context: usize,
+) -> Result<()> {
+    let suite = format_suite(suite, records, context);
+    SUITES.write(suite, |suites| {
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str("<title>Macro expansion tests</title>\n");
+        let _ = writeln!(html, "<style>{}</style>", STYLE);
+        html.push_str("</head>\n<body>\n<h1>Macro expansion tests</h1>\n");
+        html.push_str(suites);
+        html.push_str("</body>\n</html>\n");
+
+        let dir = path.parent().expect("no parent directory");
+        fs::create_dir_all(dir)?;
+        snapshot::write(path, &html)?;
+        write_index(dir)
+    })
+}
+
+/// Writes `index.html` with a link to each of the `report-<binary>.html` files in `dir`.
+fn write_index(dir: &Path) -> Result<()> {
+    let mut reports = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(REPORT_PREFIX) && name.ends_with(".html"))
+        .collect::<Vec<_>>();
+    reports.sort();
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Macro expansion tests</title>\n");
+    let _ = writeln!(html, "<style>{}</style>", STYLE);
+    html.push_str("</head>\n<body>\n<h1>Macro expansion tests</h1>\n<ul>\n");
+    for report in &reports {
+        let binary = &report[REPORT_PREFIX.len()..report.len() - ".html".len()];
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{}\">{}</a></li>",
+            escape(report),
+            escape(binary)
+        );
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    snapshot::write(&dir.join("index.html"), &html)
+}
+
+fn format_suite(suite: &str, records: &[TestRecord], context: usize) -> String {
+    let mut html = String::new();
+    let _ = writeln!(html, "<h2>{}</h2>", escape(suite));
+
+    html.push_str("<table class=\"tests\">\n<tr><th>Test</th><th>Status</th><th>Time</th></tr>\n");
+    for record in records {
+        let (class, status) = status(record);
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td class=\"{}\">{}</td><td>{:.3}s</td></tr>",
+            escape(&record.name()),
+            class,
+            status,
+            record.duration.as_secs_f64()
+        );
+    }
+    html.push_str("</table>\n");
+
+    for record in records {
+        let details = match &record.result {
+            Ok(ExpansionOutcome::Different(a, b))
+            | Ok(ExpansionOutcome::ChangedByFeatures(a, b)) => side_by_side(
+                &String::from_utf8_lossy(a),
+                &String::from_utf8_lossy(b),
+                context,
+            ),
+            Ok(ExpansionOutcome::ExpandError(stderr, errors)) => {
+                format!("<pre>{}</pre>\n", escape(&expansion_error(stderr, errors)))
+            }
+            Ok(ExpansionOutcome::ParseError(error, _)) => {
+                format!("<pre>{}</pre>\n", escape(&error.to_string()))
+            }
+            Ok(ExpansionOutcome::NoExpandedFileFound) => format!(
+                "<pre>{} is expected but not found</pre>\n",
+                escape(&record.expanded.display().to_string())
+            ),
+            Err(e) => format!("<pre>{}</pre>\n", escape(&e.to_string())),
+            Ok(ExpansionOutcome::Same) | Ok(ExpansionOutcome::Update) => continue,
+        };
+
+        let _ = write!(
+            html,
+            "<details open>\n<summary>{} &mdash; {}</summary>\n{}</details>\n",
+            escape(&record.name()),
+            status(record).1,
+            details
+        );
+    }
+
+    html
+}
+
+fn status(record: &TestRecord) -> (&'static str, &'static str) {
+    match &record.result {
+        Ok(ExpansionOutcome::Same) => ("ok", "ok"),
+        Ok(ExpansionOutcome::Update) => ("refreshed", "refreshed"),
+        Ok(ExpansionOutcome::Different(..)) => ("failed", "different"),
+        Ok(ExpansionOutcome::ExpandError(..)) => ("failed", "expansion error"),
+        Ok(ExpansionOutcome::ParseError(..)) => ("failed", "parse error"),
+        Ok(ExpansionOutcome::NoExpandedFileFound) => ("failed", "missing"),
+        Ok(ExpansionOutcome::ChangedByFeatures(..)) => ("changed", "changed by features"),
+        Err(_) => ("failed", "error"),
+    }
+}
+
+/// Formats the expected (left) and the actual (right) expansions as a side-by-side diff.
+/// Removed and added lines following each other are shown on the same rows.
+fn side_by_side(a: &str, b: &str, context: usize) -> String {
+    let changes = line_changes(a, b);
+
+    let mut html = String::from("<table class=\"diff\">\n");
+    html.push_str("<tr><th></th><th>expected</th><th></th><th>actual</th></tr>\n");
+
+    for hunk in hunks(&changes, context) {
+        let _ = writeln!(
+            html,
+            "<tr class=\"hunk\"><td colspan=\"4\">{}</td></tr>",
+            hunk.header()
+        );
+
+        let (mut old_line, mut new_line) = (hunk.old_start, hunk.new_start);
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+
+        for change in hunk.lines.iter().chain(Some(&Change::Both("", ""))) {
+            match change {
+                Change::Left(x) => {
+                    removed.push((old_line, *x));
+                    old_line += 1;
+                }
+                Change::Right(x) => {
+                    added.push((new_line, *x));
+                    new_line += 1;
+                }
+                Change::Both(x, _) => {
+                    for i in 0..removed.len().max(added.len()) {
+                        html.push_str("<tr>");
+                        push_cell(&mut html, "del", removed.get(i));
+                        push_cell(&mut html, "add", added.get(i));
+                        html.push_str("</tr>\n");
+                    }
+                    removed.clear();
+                    added.clear();
+
+                    // The sentinel after the last line of the hunk is not printed.
+                    if old_line < hunk.old_start + hunk.old_len {
+                        html.push_str("<tr>");
+                        push_cell(&mut html, "", Some(&(old_line, *x)));
+                        push_cell(&mut html, "", Some(&(new_line, *x)));
+                        html.push_str("</tr>\n");
+                    }
+                    old_line += 1;
+                    new_line += 1;
+                }
+            }
+        }
+    }
+
+    html.push_str("</table>\n");
+    html
+}
+
+fn push_cell(html: &mut String, class: &str, line: Option<&(usize, &str)>) {
+    match line {
+        Some((number, text)) => {
+            let _ = write!(
+                html,
+                "<td class=\"no\">{}</td><td class=\"{}\">{}</td>",
+                number,
+                class,
+                escape(text)
+            );
+        }
+        None => html.push_str("<td class=\"no\"></td><td></td>"),
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::expand::{ExpansionOutcome, TestRecord};
+use crate::message::format_different;
+use crate::report::{self, escape, Suites};
+use crate::snapshot;
+
+static SUITES: Suites = Suites::new();
+
+/// Appends a test suite to the JUnit XML report at `path` and rewrites the report.
+///
+/// If `path` is a directory, the report is written into a file named after the test binary,
+/// so that several test binaries don't overwrite each other's reports.
+pub(crate) fn write(
+    path: &Path,
+    suite: &str,
+    class: &str,
+    records: &[TestRecord],
+    context: usize,
+) -> Result<()> {
+    let path = if path.is_dir() {
+        path.join(format!("macrotest-{}.xml", report::binary_name()))
+    } else {
+        path.to_owned()
+    };
+
+    let suite = format_suite(suite, class, records, context);
+    SUITES.write(suite, |suites| {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+        xml.push_str(suites);
+        xml.push_str("</testsuites>\n");
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        snapshot::write(&path, &xml)
+    })
+}
+
+fn format_suite(suite: &str, class: &str, records: &[TestRecord], context: usize) -> String {
+    let mut failures = 0;
+    let mut errors = 0;
+    let mut time = Duration::ZERO;
+    let mut cases = String::new();
+
+    for record in records {
+        time += record.duration;
+
+        let _ = write!(
+            cases,
+            "    <testcase name=\"{}\" classname=\"{}\" file=\"{}\" time=\"{:.3}\"",
+            escape(&record.name()),
+            escape(class),
+            escape(&record.test.display().to_string()),
+            record.duration.as_secs_f64()
+        );
+
+        let expanded = record.expanded.display();
+        match &record.result {
+            Ok(ExpansionOutcome::Same) => cases.push_str("/>\n"),
+            Ok(ExpansionOutcome::Update) => {
+                let _ = write!(
+                    cases,
+                    ">\n      <system-out>{} - refreshed</system-out>\n    </testcase>\n",
+                    escape(&expanded.to_string())
+                );
+            }
+            Ok(ExpansionOutcome::ChangedByFeatures(a, b)) => {
+                let diff = format_different(a, b, context, false);
+                let _ = write!(
+                    cases,
+                    ">\n      <system-out>expansion changed by features\n{}</system-out>\n    </testcase>\n",
+                    escape(&diff)
+                );
+            }
+            Ok(ExpansionOutcome::Different(a, b)) => {
+                failures += 1;
+                let message = format!("expansion is different from {}", expanded);
+                let diff = format_different(a, b, context, false);
+                push_failure(&mut cases, "failure", "different", &message, &diff, None);
+            }
+            Ok(ExpansionOutcome::ExpandError(stderr, compiler_errors)) => {
+                errors += 1;
+                let stderr = String::from_utf8_lossy(stderr);
+                push_failure(
+                    &mut cases,
+                    "error",
+                    "expand-error",
+                    "expansion failed",
+                    &compiler_errors.join("\n"),
+                    Some(&stderr),
+                );
+            }
+            Ok(ExpansionOutcome::ParseError(error, _)) => {
+                failures += 1;
+                let start = error.span().start();
+                let message = format!(
+                    "expansion output could not be parsed at line {}, column {}: {}",
+                    start.line,
+                    start.column + 1,
+                    error
+                );
+                push_failure(&mut cases, "failure", "parse-error", &message, "", None);
+            }
+            Ok(ExpansionOutcome::NoExpandedFileFound) => {
+                failures += 1;
+                let message = format!("{} is expected but not found", expanded);
+                push_failure(&mut cases, "failure", "missing", &message, "", None);
+            }
+            Err(e) => {
+                errors += 1;
+                push_failure(&mut cases, "error", "error", &e.to_string(), "", None);
+            }
+        }
+    }
+
+    format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n{}  </testsuite>\n",
+        escape(suite),
+        records.len(),
+        failures,
+        errors,
+        time.as_secs_f64(),
+        cases
+    )
+}
+
+fn push_failure(
+    cases: &mut String,
+    tag: &str,
+    kind: &str,
+    message: &str,
+    body: &str,
+    stderr: Option<&str>,
+) {
+    let _ = write!(
+        cases,
+        ">\n      <{} message=\"{}\" type=\"{}\">{}</{}>\n",
+        tag,
+        escape(message),
+        kind,
+        escape(body),
+        tag
+    );
+    if let Some(stderr) = stderr {
+        let _ = writeln!(cases, "      <system-err>{}</system-err>", escape(stderr));
+    }
+    cases.push_str("    </testcase>\n");
+}
@@ -67,6 +67,44 @@
 //! cargo expand --bin <test-name> --theme none --features my-feature
 //! ```
 //!
+//! ## Enabled features
+//!
+//! The macros are expanded with the features enabled for the test, so `cargo test --features foo`
+//! expands them with `foo`. Features passed with the `_args` functions take precedence.
+//! The enabled features are detected from the build artifacts of the test binary. If that fails,
+//! a warning is printed and the default features are used; declare the features with the
+//! [`features!`] macro before each expansion to avoid it:
+//!
+//! ```rust
+//! pub fn pass() {
+//!     macrotest::features!("default", "my-feature");
+//!     macrotest::expand("tests/expand/*.rs");
+//! }
+//! ```
+//!
+//! ## Feature set matrix
+//!
+//! To check the expansion with different sets of features enabled, use
+//! [`expand_feature_sets`] or [`expand_feature_sets_without_refresh`]. Each test file is
+//! expanded once per named feature set, and the result is stored in
+//! `<stem>.<name>.expanded.rs`:
+//!
+//! ```rust
+//! pub fn pass() {
+//!     macrotest::expand_feature_sets(
+//!         "tests/expand/*.rs",
+//!         &[("std", &["std"]), ("serde", &["std", "serde"]), ("no-std", &[])],
+//!     );
+//! }
+//! ```
+//!
+//! Default features are disabled for every set, so list `default` to enable them.
+//!
+//! To find out which features affect the expansion at all, call [`expand_feature_powerset`].
+//! It expands each test file with every combination of the features listed in the `[features]`
+//! table of your `Cargo.toml`, or with each of them individually if there are more than 6,
+//! and prints the combinations that change the expansion compared to the default features.
+//!
 //! # Workflow
 //!
 //! First of all, the [`cargo expand`] tool must be present. You can install it via cargo:
@@ -82,6 +120,10 @@
 //! [current version](https://crates.io/crates/cargo-expand)
 //! and do something like `cargo install --locked --version 1.0.81 cargo-expand`.)
 //!
+//! Before expanding anything, `macrotest` checks that [`cargo expand`] is installed and recent
+//! enough. When expansion fails for a known reason, such as unstable features used on a stable
+//! toolchain or an out of date `Cargo.lock`, a suggestion is printed once per test run.
+//!
 //! ## Setting up a test project
 //!
 //! In your crate that provides procedural or declarative macros, under the `tests` directory,
@@ -114,6 +156,16 @@
 //!       respective `*.expanded.rs` files.
 //!     - If one or more `*.expanded.rs` files is not found, the test will fail.
 //!
+//! ## Running a subset of tests
+//!
+//! Set the environment variable `MACROTEST_FILTER` to run only the test files whose paths
+//! contain it, e.g. `MACROTEST_FILTER=derive_enum cargo test`. The filters passed to the test
+//! binary work as well: `cargo test -- pass derive_enum` runs the `pass` test and expands only
+//! the files matching `derive_enum`, and `--skip` excludes the files matching its pattern.
+//! Since these select the `#[test]` functions as well, filters that don't match any of the files
+//! are ignored, and so are `--skip` patterns that would exclude all of them. A warning is printed
+//! if `MACROTEST_FILTER` excludes all the files.
+//!
 //! ## Updating `.expanded.rs`
 //!
 //! This applicable only to tests that are using [`expand`] or [`expand_args`] function.
@@ -122,11 +174,147 @@
 //! files and re-run the corresponding tests. Files will be created automatically; hand-writing
 //! them is not recommended.
 //!
+//! ## Orphaned `.expanded.rs` files
+//!
+//! When a test file is renamed or deleted, its `.expanded.rs` file is left behind. Such files
+//! matched by the glob pattern are reported after the tests. Run the tests with
+//! `MACROTEST=overwrite` to remove them, or set `MACROTEST_ORPHANS=fail` to fail the test
+//! when they're found.
+//!
+//! ## Storing all expansions in one file
+//!
+//! With many test files, the `.expanded.rs` files next to them can be replaced with a single
+//! snapshot file per glob pattern by setting the environment variable
+//! `MACROTEST_STORE=single-file`, e.g. in the `[env]` section of `.cargo/config.toml`.
+//! The expansions are stored in `macrotest.snap` in the directory the glob pattern starts
+//! with, such as `tests/expand/macrotest.snap` for `tests/expand/*.rs`, with a section for
+//! each test file:
+//!
+//! ```text
+//! //@ snapshot: first.expanded.rs
+//! pub fn main() {}
+//! ```
+//!
+//! The single file works the same way as separate files: it's updated with
+//! `MACROTEST=overwrite`, sections of deleted test files are reported as orphaned, and
+//! the pending changes are written to `macrotest.snap.new`. `MACROTEST_STORE=files`
+//! selects the default behavior.
+//!
+//! ## Reviewing changes
+//!
+//! When an expansion differs from the `.expanded.rs` file, or the file is expected but missing,
+//! the new expansion is written next to it as a pending `.expanded.rs.new` file. Set the
+//! `MACROTEST_PENDING` environment variable to a directory to write the pending files there
+//! instead, mirroring the paths of the `.expanded.rs` files. Pending files are removed once
+//! the test passes.
+//!
+//! Pending files can be reviewed with the `cargo-macrotest` binary shipped with this crate:
+//!
+//! ```bash
+//! cargo install macrotest
+//! cargo macrotest review
+//! ```
+//!
+//! It shows the diff for each of the pending files and asks to accept or reject it.
+//! `cargo macrotest accept` and `cargo macrotest reject` handle all of them at once.
+//! You may want to add `*.expanded.rs.new` to your `.gitignore`.
+//!
+//! Line endings, a byte order mark, trailing whitespace and the final newline are ignored
+//! when `.expanded.rs` files are compared, so checking them out with CRLF line endings
+//! doesn't fail the tests.
+//!
+//! ## Checking without writing files
+//!
+//! Run tests with the environment variable `MACROTEST=check` to make sure that nothing is written:
+//! all `*.expanded.rs` files are expected to exist, as with [`expand_without_refresh`], even if
+//! the test calls [`expand`], and no pending files are created. This mode is enabled by default
+//! when the `CI` environment variable is set, so that a forgotten `.expanded.rs` file fails CI
+//! instead of being silently created there. `MACROTEST=overwrite` takes precedence over `CI`.
+//!
+//! ## Offline builds
+//!
+//! The tests are expanded in a temporary crate that uses a copy of the workspace `Cargo.lock`.
+//! Set the environment variable `MACROTEST_RESOLVE` to `offline`, `locked` or `frozen` to pass
+//! the corresponding flag to every cargo invocation. The temporary crate is then resolved
+//! first, offline unless only `locked` is set, and with `locked` or `frozen` the packages it
+//! needs that are missing from `Cargo.lock` are reported, instead of cargo failing because the
+//! lockfile needs to be updated.
+//!
+//! ## Comparing expansions
+//!
+//! When an expansion differs from the `.expanded.rs` file, a unified diff is printed with
+//! 3 lines of context around each change. The number of context lines can be changed with the
+//! `MACROTEST_DIFF_CONTEXT` environment variable. The diff is colored when printed to a terminal,
+//! unless the `NO_COLOR` environment variable is set. Within a changed line, the tokens that
+//! differ are highlighted, or marked with `^` when colors are disabled.
+//!
+//! By default, the expansion result is compared with the `.expanded.rs` file line by line.
+//! This means that a change in formatting (for example, after an upgrade of the pretty-printer)
+//! fails the test even if the expanded code is the same.
+//!
+//! Set the environment variable `MACROTEST_COMPARE=ast` to parse both sides and compare them
+//! as token streams instead, ignoring formatting and comments. The text diff is still printed
+//! to show what differs. `MACROTEST_COMPARE=text` selects the default behavior.
+//!
+//! If the order of generated items doesn't matter, use `MACROTEST_COMPARE=unordered` to compare
+//! top-level items as a multiset, or `MACROTEST_COMPARE=unordered-nested` to also ignore the
+//! order of items inside inline modules. In these modes, `.expanded.rs` files are written with
+//! items sorted in a canonical order.
+//!
+//! ## Formatting
+//!
+//! The expanded code is formatted with [`prettyplease`] by default. Set the environment variable
+//! `MACROTEST_FORMATTER=rustfmt` to format it with `rustfmt` instead, which respects the
+//! `rustfmt.toml` of your crate. The `rustfmt` component must be installed in this case:
+//!
+//! ```bash
+//! rustup component add rustfmt
+//! ```
+//!
+//! Note that switching the formatter changes the content of all `.expanded.rs` files,
+//! so they have to be regenerated.
+//!
+//! ## HTML report
+//!
+//! Every test binary writes a self-contained HTML report to
+//! `target/macrotest/report-<binary>.html`, e.g. `report-tests.html` for `tests/tests.rs`.
+//! It lists each test file with its status and timing, and shows a side-by-side diff of the
+//! expected and the actual expansion for the failed tests. The report contains all the `expand`
+//! calls made by the last run of the binary, and `target/macrotest/index.html` links the reports
+//! of all the test binaries.
+//!
+//! ## JUnit XML reports
+//!
+//! Set the environment variable `MACROTEST_JUNIT` to a file path to write a JUnit XML report
+//! with a test case for each expansion test file. Every call to one of the `expand` functions
+//! is reported as a separate test suite. If the path points to an existing directory, the report
+//! is written into a file named after the test binary, e.g. `macrotest-tests.xml`.
+//!
+//! ## JSON output
+//!
+//! Set the environment variable `MACROTEST_FORMAT=json` to print events as JSON lines on `STDOUT`
+//! instead of the human-readable messages, for consumption by editor integrations and scripts.
+//! Each line is an object with the `event` field set to one of `run_started`, `build_started`,
+//! `build_finished`, `test`, `orphan` and `run_finished`, and the `suite` field set to the glob
+//! pattern passed to the `expand` function. `test` events contain the `path` of the test file,
+//! the `expanded` file path, the `outcome` and the `duration` in seconds.
+//! Remember to pass `--nocapture` to the test binary so that the output is not captured.
+//!
+//! ## Unparsable expansions
+//!
+//! The expansion result is parsed with `syn` to strip the prelude injected by the compiler and
+//! to format the code. If parsing fails, a warning with the location of the error is printed and
+//! the raw output is compared instead. Set `MACROTEST_PARSE_ERROR=fail` to make it a test failure.
+//!
 //! [`expand_without_refresh`]: expand/fn.expand_without_refresh.html
 //! [`expand_without_refresh_args`]: expand/fn.expand_without_refresh_args.html
 //! [`expand`]: expand/fn.expand.html
 //! [`expand_args`]: expand/fn.expand_args.html
+//! [`expand_feature_powerset`]: expand/fn.expand_feature_powerset.html
+//! [`expand_feature_sets`]: expand/fn.expand_feature_sets.html
+//! [`expand_feature_sets_without_refresh`]: expand/fn.expand_feature_sets_without_refresh.html
 //! [trybuild]: https://github.com/dtolnay/trybuild
+//! [`prettyplease`]: https://github.com/dtolnay/prettyplease
 //! [`cargo expand`]: https://github.com/dtolnay/cargo-expand
 
 #![allow(clippy::lines_filter_map_ok)] // https://github.com/rust-lang/rust-clippy/issues/14127
@@ -135,15 +323,67 @@
 mod path;
 
 mod cargo;
+mod compare;
+mod config;
 mod dependencies;
 mod error;
+mod events;
 mod expand;
 mod features;
+mod filter;
+mod format;
+mod highlight;
+mod html;
+mod junit;
 mod manifest;
 mod message;
+mod powerset;
+mod report;
+mod review;
 mod rustflags;
+mod snapshot;
+mod store;
 
 pub use expand::expand;
 pub use expand::expand_args;
+pub use expand::expand_feature_powerset;
+pub use expand::expand_feature_sets;
+pub use expand::expand_feature_sets_without_refresh;
 pub use expand::expand_without_refresh;
 pub use expand::expand_without_refresh_args;
+
+#[doc(hidden)]
+pub use review::main as __cargo_macrotest_main;
+
+#[doc(hidden)]
+pub use features::__set_features;
+
+/// Declares which features of the crate are enabled for the tests, so that the macros are
+/// expanded with the same features.
+///
+/// List all the features of your crate that affect the expansion. Each of them is checked
+/// with `cfg!(feature = ...)` in the test crate, and the enabled ones are passed to
+/// `cargo expand`. If `"default"` is listed and disabled, the expansion runs with
+/// `--no-default-features`.
+///
+/// ```rust
+/// # /*
+/// #[test]
+/// # */
+/// pub fn pass() {
+///     macrotest::features!("default", "serde", "std");
+///     macrotest::expand("tests/expand/*.rs");
+/// }
+/// ```
+///
+/// The declaration only applies to the next expansion function called on the same thread,
+/// so call the macro right before each of them. Other tests of the binary are not affected.
+///
+/// Without this macro, the features are detected from the build artifacts of the test binary,
+/// and a warning is printed if that fails.
+#[macro_export]
+macro_rules! features {
+    ($($feature:tt),* $(,)?) => {
+        $crate::__set_features(&[$(($feature, cfg!(feature = $feature))),*])
+    };
+}
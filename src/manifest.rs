@@ -1,6 +1,5 @@
 use crate::dependencies::{Dependency, Patch, RegistryPatch};
-use serde_derive::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_derive::Serialize;
 use std::collections::BTreeMap as Map;
 use std::ffi::OsStr;
 use std::path::PathBuf;
@@ -14,6 +13,8 @@ pub struct Manifest {
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub features: Map<String, Vec<String>>,
     pub dependencies: Map<String, Dependency>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub target: Map<String, Target>,
     #[serde(rename = "bin")]
     pub bins: Vec<Bin>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -24,18 +25,19 @@ pub struct Manifest {
     pub replace: Map<String, Patch>,
 }
 
+#[derive(Serialize, Default, Debug)]
+pub struct Target {
+    pub dependencies: Map<String, Dependency>,
+}
+
 #[derive(Serialize, Debug)]
 pub struct Package {
     pub name: String,
     pub version: String,
     pub publish: bool,
-    pub edition: Edition,
+    pub edition: String,
 }
 
-// Do not use enum for edition for future-compatibility.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Edition(pub Value);
-
 #[derive(Serialize, Debug)]
 pub struct Bin {
     pub name: Name,
@@ -48,6 +50,9 @@ pub struct Name(pub String);
 #[derive(Serialize, Debug)]
 pub struct Config {
     pub build: Build,
+    /// The sections carried over from the configuration of the source workspace.
+    #[serde(flatten)]
+    pub source: Map<String, toml::Value>,
 }
 
 #[derive(Serialize, Debug)]
@@ -55,31 +60,9 @@ pub struct Build {
     pub rustflags: Vec<String>,
 }
 
+/// An empty `[workspace]` section, which keeps the generated crate out of the source workspace.
 #[derive(Serialize, Debug)]
-pub struct Workspace {
-    #[serde(skip_serializing_if = "WorkspacePackage::is_none")]
-    pub package: WorkspacePackage,
-    #[serde(skip_serializing_if = "Map::is_empty")]
-    pub dependencies: Map<String, Dependency>,
-}
-
-#[derive(Serialize, Debug)]
-pub struct WorkspacePackage {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub edition: Option<String>,
-}
-
-impl WorkspacePackage {
-    fn is_none(&self) -> bool {
-        self.edition.is_none()
-    }
-}
-
-impl Default for Edition {
-    fn default() -> Self {
-        Self("2021".into())
-    }
-}
+pub struct Workspace {}
 
 impl AsRef<OsStr> for Name {
     fn as_ref(&self) -> &OsStr {
@@ -1,65 +1,483 @@
+use std::env;
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Mutex, Once};
+
+use crate::highlight::{self, LineTokens};
 use diff::Result;
 
-/// Prints the difference of the two snippets of expanded code.
-pub(crate) fn message_different(name: &str, a: &[u8], b: &[u8]) {
+const RED: &str = "31";
+const GREEN: &str = "32";
+const CYAN: &str = "36";
+
+/// Returns `true` if the messages printed to `STDERR` should be colored.
+/// Follows the <https://no-color.org> convention.
+pub(crate) fn use_color() -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && std::io::stderr().is_terminal()
+}
+
+fn paint(color: bool, code: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Writes a changed line, marking the tokens that differ within it.
+///
+/// Without colors, the changed tokens are marked with carets on the following line.
+fn write_changed_line(
+    out: &mut String,
+    color: bool,
+    code: &str,
+    prefix: char,
+    line: &str,
+    tokens: &[Range<usize>],
+) {
+    let segments = highlight::segments(line, tokens);
+
+    if color {
+        let mut painted = paint(color, code, &prefix.to_string());
+        for (segment, changed) in segments {
+            if changed {
+                painted.push_str(&paint(color, &format!("{};7", code), segment));
+            } else {
+                painted.push_str(&paint(color, code, segment));
+            }
+        }
+        let _ = writeln!(out, "{}", painted);
+        return;
+    }
+
+    let _ = writeln!(out, "{}{}", prefix, line);
+    if !tokens.is_empty() {
+        let mut carets = String::from(" ");
+        for (segment, changed) in segments {
+            let mark = if changed { "^" } else { " " };
+            carets.push_str(&mark.repeat(segment.chars().count()));
+        }
+        let _ = writeln!(out, "{}", carets.trim_end());
+    }
+}
+
+/// Diffs the two texts line by line.
+pub(crate) fn line_changes<'a>(a: &'a str, b: &'a str) -> Vec<Result<&'a str>> {
+    let mut changes = diff::lines(a, b);
+
+    // `diff` reports an empty line after the final newline of both texts, it's not a real line.
+    if let Some(Result::Both("", "")) = changes.last() {
+        changes.pop();
+    }
+
+    changes
+}
+
+/// A group of changed lines surrounded by unchanged context lines.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Hunk<'a> {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<Result<&'a str>>,
+}
+
+impl Hunk<'_> {
+    /// Formats the `@@ -l,s +l,s @@` header of the hunk.
+    pub(crate) fn header(&self) -> String {
+        format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_len, self.new_start, self.new_len
+        )
+    }
+}
+
+/// Splits the line changes into hunks with `context` unchanged lines around each change.
+pub(crate) fn hunks<'a>(changes: &[Result<&'a str>], context: usize) -> Vec<Hunk<'a>> {
+    // Line numbers (1-indexed) of both sides at each of the changes.
+    let mut positions = Vec::with_capacity(changes.len());
+    let (mut old_line, mut new_line) = (1, 1);
+    for change in changes {
+        positions.push((old_line, new_line));
+        match change {
+            Result::Both(..) => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Result::Left(_) => old_line += 1,
+            Result::Right(_) => new_line += 1,
+        }
+    }
+
+    // Ranges of changes to print, merged if their context overlaps.
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (i, change) in changes.iter().enumerate() {
+        if let Result::Both(..) = change {
+            continue;
+        }
+
+        let start = i.saturating_sub(context);
+        let end = (i + context + 1).min(changes.len());
+        match ranges.last_mut() {
+            Some(last) if last.1 >= start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let lines = changes[start..end].to_vec();
+            let old_len = lines
+                .iter()
+                .filter(|l| !matches!(l, Result::Right(_)))
+                .count();
+            let new_len = lines
+                .iter()
+                .filter(|l| !matches!(l, Result::Left(_)))
+                .count();
+            let (old_start, new_start) = positions[start];
+
+            // An empty side of a hunk refers to the line before it.
+            Hunk {
+                old_start: if old_len == 0 {
+                    old_start - 1
+                } else {
+                    old_start
+                },
+                old_len,
+                new_start: if new_len == 0 {
+                    new_start - 1
+                } else {
+                    new_start
+                },
+                new_len,
+                lines,
+            }
+        })
+        .collect()
+}
+
+/// Prints the difference of the two snippets of expanded code as a unified diff
+/// with `context` unchanged lines around each change.
+///
+/// `a` is the expected expansion and `b` is the actual one.
+pub(crate) fn message_different(name: &str, a: &[u8], b: &[u8], context: usize) {
+    eprintln!("{} - different!", name);
+    eprint!("{}", format_different(a, b, context, use_color()));
+}
+
+/// Formats the difference of the two snippets of expanded code, see [`message_different`].
+pub(crate) fn format_different(a: &[u8], b: &[u8], context: usize, color: bool) -> String {
     let a = String::from_utf8_lossy(a);
     let b = String::from_utf8_lossy(b);
 
-    let changes = diff::lines(&a, &b);
+    let changes = line_changes(&a, &b);
 
     let mut lines_added = 0;
     let mut lines_removed = 0;
     for diff in &changes {
         match diff {
-            Result::Left(_) => lines_added += 1,
-            Result::Right(_) => lines_removed += 1,
+            Result::Left(_) => lines_removed += 1,
+            Result::Right(_) => lines_added += 1,
             _ => (),
         }
     }
 
-    eprintln!("{} - different!", name);
+    let old_tokens = LineTokens::new(&a);
+    let new_tokens = LineTokens::new(&b);
 
-    eprintln!(
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
         "Diff [lines: {} added, {} removed]:",
         lines_added, lines_removed
     );
-    eprintln!("--------------------------");
+    let _ = writeln!(out, "--------------------------");
+    let _ = writeln!(out, "{}", paint(color, RED, "--- expected"));
+    let _ = writeln!(out, "{}", paint(color, GREEN, "+++ actual"));
 
-    for change in changes {
-        match change {
-            Result::Both(x, _) => {
-                eprintln!(" {}", x);
+    for hunk in hunks(&changes, context) {
+        let _ = writeln!(out, "{}", paint(color, CYAN, &hunk.header()));
+
+        let (mut old_line, mut new_line) = (hunk.old_start, hunk.new_start);
+        let highlights =
+            highlight::highlights(&hunk.lines, (old_line, new_line), &old_tokens, &new_tokens);
+
+        for line in hunk.lines {
+            match line {
+                Result::Both(x, _) => {
+                    let _ = writeln!(out, " {}", x);
+                    old_line += 1;
+                    new_line += 1;
+                }
+                Result::Left(x) => {
+                    let tokens = highlights.old.get(&old_line).map_or(&[][..], Vec::as_slice);
+                    write_changed_line(&mut out, color, RED, '-', x, tokens);
+                    old_line += 1;
+                }
+                Result::Right(x) => {
+                    let tokens = highlights.new.get(&new_line).map_or(&[][..], Vec::as_slice);
+                    write_changed_line(&mut out, color, GREEN, '+', x, tokens);
+                    new_line += 1;
+                }
             }
-            Result::Left(x) => {
-                eprintln!("+{}", x);
+        }
+    }
+
+    let _ = writeln!(out, "--------------------------");
+    out
+}
+
+/// Warns once that the features of the test binary are unknown, so the default features
+/// are used for the expansion.
+pub(crate) fn message_features_not_detected() {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        eprintln!("WARNING: could not detect the features enabled for the tests,");
+        eprintln!("the macros are expanded with the default features.");
+        eprintln!("Call `macrotest::features!(\"feature\", ...)` with the features of your crate");
+        eprintln!("before expanding to pass the enabled ones on.");
+        eprintln!();
+    });
+}
+
+pub(crate) fn message_all_filtered_out(suite: &str, total: usize) {
+    eprintln!(
+        "WARNING: all {} test files matching `{}` are filtered out by MACROTEST_FILTER.",
+        total, suite
+    );
+    eprintln!();
+}
+
+/// Prints the combinations of features that change the expansion of the test,
+/// and those under which the expansion fails.
+pub(crate) fn message_feature_changes(name: &str, changed: &[&str], failed: &[&str]) {
+    fn list(combinations: &[&str]) -> String {
+        combinations
+            .iter()
+            .map(|c| format!("`{}`", c))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    if changed.is_empty() && failed.is_empty() {
+        eprintln!("{} - not affected by features", name);
+        return;
+    }
+    if !changed.is_empty() {
+        eprintln!("{} - expansion changed by {}", name, list(changed));
+    }
+    if !failed.is_empty() {
+        eprintln!("{} - expansion failed with {}", name, list(failed));
+    }
+}
+
+/// Prints a warning about the `.expanded.rs` file whose test file doesn't exist anymore.
+pub(crate) fn message_orphan(orphan: &str, test: &Path, removed: bool) {
+    if removed {
+        eprintln!("{} - removed, {} not found", orphan, test.display());
+    } else {
+        eprintln!("{} - orphaned, {} not found", orphan, test.display());
+        eprintln!("Run tests with MACROTEST=overwrite to remove it.");
+        eprintln!();
+    }
+}
+
+/// Explains why a missing `.expanded.rs` file wasn't created.
+pub(crate) fn message_check_mode() {
+    eprintln!("Files are never written with MACROTEST=check, which is the default in CI.");
+    eprintln!("Run the tests locally to create the file and commit it.");
+    eprintln!();
+}
+
+/// Prints where the new expansion is written and how to review it.
+pub(crate) fn message_pending(pending: &Path) {
+    eprintln!("The new expansion is written to {}", pending.display());
+    eprintln!("Run `cargo macrotest review` to accept or reject it.");
+    eprintln!();
+}
+
+/// A known cause of expansion failures with a suggestion how to fix it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Hint {
+    CargoExpandNotInstalled,
+    CargoExpandOutdated,
+    RustfmtNotInstalled,
+    NightlyFeatures,
+    LockfileOutdated,
+    OfflineIndexMissing,
+}
+
+impl Hint {
+    /// Finds the known causes of the failure in the `STDERR` of a cargo invocation.
+    pub(crate) fn find(stderr: &str) -> Vec<Hint> {
+        let mut hints = Vec::new();
+
+        if stderr.contains("no such subcommand: `expand`")
+            || stderr.contains("no such command: `expand`")
+        {
+            hints.push(Hint::CargoExpandNotInstalled);
+        }
+        if stderr.contains("--theme")
+            && (stderr.contains("wasn't expected") || stderr.contains("unexpected argument"))
+        {
+            hints.push(Hint::CargoExpandOutdated);
+        }
+        if stderr.contains("rustfmt")
+            && (stderr.contains("not installed") || stderr.contains("not found"))
+        {
+            hints.push(Hint::RustfmtNotInstalled);
+        }
+        if stderr.contains("may not be used on the stable release channel")
+            || stderr.contains("E0554")
+        {
+            hints.push(Hint::NightlyFeatures);
+        }
+        if stderr.contains("needs to be updated but --locked was passed") {
+            hints.push(Hint::LockfileOutdated);
+        }
+        if stderr.contains("--offline") || stderr.contains("offline mode") {
+            hints.push(Hint::OfflineIndexMissing);
+        }
+
+        hints
+    }
+
+    fn print(self) {
+        match self {
+            Hint::CargoExpandNotInstalled => {
+                eprintln!("Perhaps, `cargo expand` is not installed?");
+                eprintln!("Install it by running:");
+                eprintln!();
+                eprintln!("\tcargo install cargo-expand");
+            }
+            Hint::CargoExpandOutdated => {
+                eprintln!("Perhaps, `cargo expand` is too old to support the `--theme` flag?");
+                eprintln!("Update it by running:");
+                eprintln!();
+                eprintln!("\tcargo install --force cargo-expand");
+            }
+            Hint::RustfmtNotInstalled => {
+                eprintln!("Perhaps, the `rustfmt` component is not installed?");
+                eprintln!("Install it by running:");
+                eprintln!();
+                eprintln!("\trustup component add rustfmt");
+            }
+            Hint::NightlyFeatures => {
+                eprintln!("The code uses unstable features that require a nightly toolchain.");
+                eprintln!("Run the tests with a nightly toolchain, for example:");
+                eprintln!();
+                eprintln!("\tcargo +nightly test");
             }
-            Result::Right(x) => {
-                eprintln!("-{}", x);
+            Hint::LockfileOutdated => {
+                eprintln!("`Cargo.lock` is out of date, but `--locked` was passed.");
+                eprintln!("The lockfile of your workspace is copied into the temporary crate;");
+                eprintln!("update it by running `cargo update` or `cargo generate-lockfile`.");
+            }
+            Hint::OfflineIndexMissing => {
+                eprintln!("Cargo runs offline, but the registry index or some of the packages");
+                eprintln!("are not available locally. Fetch them while online by running:");
+                eprintln!();
+                eprintln!("\tcargo fetch");
             }
         }
+        eprintln!();
     }
+}
 
-    eprintln!("--------------------------");
+/// Hints that have already been printed by the current test binary.
+static HINTS_SHOWN: Mutex<Vec<Hint>> = Mutex::new(Vec::new());
+
+/// Prints the suggestions for the known causes of a failure.
+/// Each suggestion is printed once per run, rather than for each test.
+pub(crate) fn message_hints(hints: &[Hint]) {
+    let mut shown = HINTS_SHOWN.lock().unwrap_or_else(|e| e.into_inner());
+    for &hint in hints {
+        if !shown.contains(&hint) {
+            shown.push(hint);
+            hint.print();
+        }
+    }
 }
 
 /// Prints an error from `cargo expand` invocation.
+/// Prints the compiler errors if there are any, otherwise prints the raw `STDERR`.
 /// Makes some suggestions when possible.
-pub(crate) fn message_expansion_error(msg: Vec<u8>) {
-    let msg = String::from_utf8(msg);
+pub(crate) fn message_expansion_error(name: &str, msg: &[u8], errors: &[String]) {
+    let msg = String::from_utf8_lossy(msg);
 
-    eprintln!("Expansion error:");
-    if let Ok(msg) = msg {
+    if errors.is_empty() {
+        eprintln!("{} - expansion error:", name);
         eprintln!("{}", msg);
-
-        // No `cargo expand` subcommand installed, make a suggestion
-        if msg.contains("no such subcommand: `expand`") {
-            eprintln!("Perhaps, `cargo expand` is not installed?");
-            eprintln!("Install it by running:");
-            eprintln!();
-            eprintln!("\tcargo install cargo-expand");
+    } else {
+        eprintln!("{} - expansion failed:", name);
+        for error in errors {
+            eprintln!("{}", error.trim_end());
             eprintln!();
         }
-    } else {
-        eprintln!("<unprintable>");
     }
+
+    message_hints(&Hint::find(&msg));
+}
+
+/// Prints a warning about the expansion output that `syn` failed to parse.
+/// Such output is compared as is, without normalization.
+pub(crate) fn message_parse_warning(name: &str, error: &syn::Error, code: &str) {
+    eprintln!("{} - warning: expansion output could not be parsed", name);
+    print_parse_error(error, code);
+    eprintln!("The output is compared without stripping the prelude and reformatting.");
+    eprintln!("Set MACROTEST_PARSE_ERROR=fail to treat this as a test failure.");
+    eprintln!();
+}
+
+/// Prints an error about the expansion output that `syn` failed to parse.
+pub(crate) fn message_parse_error(name: &str, error: &syn::Error, code: &str) {
+    eprintln!("{} - expansion output could not be parsed!", name);
+    print_parse_error(error, code);
+    eprintln!("Perhaps, the expansion contains syntax that this version of `syn` can't parse?");
+    eprintln!();
+}
+
+fn print_parse_error(error: &syn::Error, code: &str) {
+    let start = error.span().start();
+
+    eprintln!(
+        "--> line {}, column {}: {}",
+        start.line,
+        start.column + 1,
+        error
+    );
+
+    // Lines are 1-indexed, zero means that the location is unknown.
+    if let Some(line) = start.line.checked_sub(1).and_then(|i| code.lines().nth(i)) {
+        let number = start.line.to_string();
+        let padding = " ".repeat(number.len());
+        eprintln!("{} |", padding);
+        eprintln!("{} | {}", number, line);
+        eprintln!("{} | {}^", padding, " ".repeat(start.column));
+    }
+}
+
+#[test]
+fn test_hunks() {
+    let a = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+    let b = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n";
+    let changes = line_changes(a, b);
+
+    let headers = hunks(&changes, 1)
+        .iter()
+        .map(Hunk::header)
+        .collect::<Vec<_>>();
+    assert_eq!(headers, ["@@ -2,3 +2,3 @@", "@@ -9,1 +9,2 @@"]);
+
+    let headers = hunks(&changes, 3)
+        .iter()
+        .map(Hunk::header)
+        .collect::<Vec<_>>();
+    assert_eq!(headers, ["@@ -1,9 +1,10 @@"]);
 }
@@ -0,0 +1,40 @@
+/// The largest number of features for which every combination is expanded.
+/// With more features, each of them is expanded individually.
+pub(crate) const MAX_POWERSET_FEATURES: usize = 6;
+
+/// Returns the non-empty combinations of `features` to expand, smallest first.
+///
+/// If there are more than `max` features, returns each of them individually instead,
+/// since the number of combinations grows exponentially.
+pub(crate) fn combinations(features: &[String], max: usize) -> Vec<Vec<String>> {
+    if features.len() > max {
+        return features.iter().map(|f| vec![f.clone()]).collect();
+    }
+
+    let mut combinations = (1u64..1 << features.len())
+        .map(|mask| {
+            features
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, feature)| feature.clone())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    combinations.sort_by_key(Vec::len);
+    combinations
+}
+
+#[test]
+fn test_combinations() {
+    let features = ["a", "b", "c"].map(String::from);
+
+    let all = combinations(&features, MAX_POWERSET_FEATURES);
+    assert_eq!(all.len(), 7);
+    assert_eq!(all[0], ["a"]);
+    assert_eq!(all[3], ["a", "b"]);
+    assert_eq!(all[6], ["a", "b", "c"]);
+
+    let individual = combinations(&features, 2);
+    assert_eq!(individual, [["a"], ["b"], ["c"]]);
+}
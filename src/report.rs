@@ -0,0 +1,72 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::error::Result;
+
+/// Test suites reported by the current test binary so far.
+///
+/// Every `expand*` call is a separate test suite, and all of them are written into one file.
+pub(crate) struct Suites(Mutex<Vec<String>>);
+
+impl Suites {
+    pub(crate) const fn new() -> Self {
+        Suites(Mutex::new(Vec::new()))
+    }
+
+    /// Adds a formatted suite and calls `write` with all the suites reported so far.
+    ///
+    /// The lock is held while the report is written, so that the tests running in parallel
+    /// don't overwrite a newer report with an older one.
+    pub(crate) fn write(
+        &self,
+        suite: String,
+        write: impl FnOnce(&str) -> Result<()>,
+    ) -> Result<()> {
+        let mut suites = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        suites.push(suite);
+        write(&suites.concat())
+    }
+}
+
+/// The name of the running test binary without the hash cargo appends to it.
+pub(crate) fn binary_name() -> String {
+    let binary = env::args_os().next().map(PathBuf::from).unwrap_or_default();
+    let stem = binary
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    match stem.rsplit_once('-') {
+        Some((name, hash)) if hash.bytes().all(|b| b.is_ascii_hexdigit()) => name.to_owned(),
+        _ => stem,
+    }
+}
+
+/// Returns the errors reported by the compiler, or the raw `STDERR` of `cargo expand`
+/// if the expansion failed for another reason.
+pub(crate) fn expansion_error(stderr: &[u8], errors: &[String]) -> String {
+    if errors.is_empty() {
+        String::from_utf8_lossy(stderr).into_owned()
+    } else {
+        errors.join("\n")
+    }
+}
+
+/// Escapes the text for HTML and XML, dropping the control characters XML doesn't allow.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(ch),
+            ch if ch.is_control() => {}
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,239 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::error::{Error, Result};
+use crate::message::{format_different, use_color};
+use crate::snapshot::{self, PENDING_SUFFIX};
+use crate::store::SINGLE_FILE_NAME;
+
+const USAGE: &str = "\
+Review pending macro expansion snapshots.
+
+Usage: cargo macrotest <COMMAND> [--pending-dir <DIR>]
+
+Commands:
+  review  Show the diff of each pending snapshot and accept or reject it
+  accept  Accept all pending snapshots
+  reject  Reject all pending snapshots
+
+Options:
+  --pending-dir <DIR>  Directory with pending snapshots, defaults to MACROTEST_PENDING
+                       or to the snapshots next to the `.expanded.rs` files";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Command {
+    Review,
+    Accept,
+    Reject,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Decision {
+    Accept,
+    Reject,
+    Skip,
+    Quit,
+}
+
+/// A pending snapshot and the `.expanded.rs` file it's going to replace.
+struct Pending {
+    pending: PathBuf,
+    expanded: PathBuf,
+}
+
+/// The entry point of the `cargo-macrotest` binary.
+pub fn main() -> ExitCode {
+    // Skip the binary name and the subcommand name passed by cargo.
+    let mut args = env::args_os().skip(1).peekable();
+    if args.peek().is_some_and(|arg| arg == "macrotest") {
+        args.next();
+    }
+
+    match parse_args(args).and_then(|(command, dir)| run(command, dir.as_deref())) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage(msg)) => {
+            eprintln!("error: {}\n\n{}", msg, USAGE);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = OsString>) -> Result<(Command, Option<PathBuf>)> {
+    let mut command = None;
+    let mut pending_dir = env::var_os("MACROTEST_PENDING")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from);
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("review") => command = Some(Command::Review),
+            Some("accept") => command = Some(Command::Accept),
+            Some("reject") => command = Some(Command::Reject),
+            Some("--pending-dir") => match args.next() {
+                Some(dir) => pending_dir = Some(PathBuf::from(dir)),
+                None => return Err(Error::Usage("--pending-dir requires a value".to_owned())),
+            },
+            Some("-h") | Some("--help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => {
+                let arg = arg.to_string_lossy();
+                return Err(Error::Usage(format!("unexpected argument `{}`", arg)));
+            }
+        }
+    }
+
+    let command = command.ok_or_else(|| Error::Usage("no command given".to_owned()))?;
+    Ok((command, pending_dir))
+}
+
+fn run(command: Command, pending_dir: Option<&Path>) -> Result<()> {
+    let pending = find_pending(pending_dir)?;
+    if pending.is_empty() {
+        eprintln!("No pending snapshots.");
+        return Ok(());
+    }
+
+    let (mut accepted, mut rejected, mut skipped) = (0, 0, 0);
+    let total = pending.len();
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    for (i, snapshot) in pending.iter().enumerate() {
+        let decision = match command {
+            Command::Accept => Decision::Accept,
+            Command::Reject => Decision::Reject,
+            Command::Review => {
+                show(snapshot, i + 1, total)?;
+                ask(&mut input)?
+            }
+        };
+
+        match decision {
+            Decision::Accept => {
+                let contents = snapshot::read(&snapshot.pending)?;
+                if let Some(parent) = snapshot.expanded.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                snapshot::write(&snapshot.expanded, &contents)?;
+                fs::remove_file(&snapshot.pending)?;
+                eprintln!("{} - accepted", snapshot.expanded.display());
+                accepted += 1;
+            }
+            Decision::Reject => {
+                fs::remove_file(&snapshot.pending)?;
+                eprintln!("{} - rejected", snapshot.expanded.display());
+                rejected += 1;
+            }
+            Decision::Skip => skipped += 1,
+            Decision::Quit => {
+                skipped += total - i;
+                break;
+            }
+        }
+    }
+
+    eprintln!(
+        "{} accepted, {} rejected, {} skipped",
+        accepted, rejected, skipped
+    );
+    Ok(())
+}
+
+fn show(snapshot: &Pending, index: usize, total: usize) -> Result<()> {
+    let new = snapshot::read(&snapshot.pending)?;
+    let old = if snapshot.expanded.exists() {
+        snapshot::read(&snapshot.expanded)?
+    } else {
+        String::new()
+    };
+
+    eprintln!();
+    eprintln!("[{}/{}] {}", index, total, snapshot.expanded.display());
+    if old.is_empty() {
+        eprintln!("New snapshot:");
+    }
+    eprint!(
+        "{}",
+        format_different(old.as_bytes(), new.as_bytes(), 3, use_color())
+    );
+    Ok(())
+}
+
+fn ask(input: &mut impl BufRead) -> Result<Decision> {
+    loop {
+        eprint!("[a]ccept, [r]eject, [s]kip, [q]uit: ");
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(Decision::Quit);
+        }
+
+        match line.trim() {
+            "a" | "accept" => return Ok(Decision::Accept),
+            "r" | "reject" => return Ok(Decision::Reject),
+            "s" | "skip" => return Ok(Decision::Skip),
+            "q" | "quit" => return Ok(Decision::Quit),
+            _ => continue,
+        }
+    }
+}
+
+/// Finds the pending snapshots in the pending directory or, if it's not set,
+/// in the current directory, skipping `target` and hidden directories.
+fn find_pending(pending_dir: Option<&Path>) -> Result<Vec<Pending>> {
+    let root = pending_dir.unwrap_or_else(|| Path::new("."));
+    let suffix = format!(".expanded.rs.{}", PENDING_SUFFIX);
+    let single_file = format!("{}.{}", SINGLE_FILE_NAME, PENDING_SUFFIX);
+
+    let mut files = Vec::new();
+    if root.is_dir() {
+        walk(root, pending_dir.is_none(), &mut files)?;
+    }
+    files.retain(|path| {
+        path.to_string_lossy().ends_with(&suffix)
+            || path.file_name().is_some_and(|name| *name == *single_file)
+    });
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .map(|pending| {
+            let mut expanded = pending.with_extension("");
+            if let Some(stripped) = pending_dir.and_then(|dir| expanded.strip_prefix(dir).ok()) {
+                expanded = stripped.to_owned();
+            }
+            let expanded = expanded.strip_prefix(".").unwrap_or(&expanded).to_owned();
+            Pending { pending, expanded }
+        })
+        .collect())
+}
+
+fn walk(dir: &Path, skip_build_dirs: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if skip_build_dirs && (name == "target" || name.starts_with('.')) {
+                continue;
+            }
+            walk(&path, skip_build_dirs, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
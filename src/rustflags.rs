@@ -1,8 +1,19 @@
+use std::collections::BTreeMap as Map;
 use std::env;
+use std::ffi::OsString;
+use std::path::Path;
 use std::process::Command;
 
+use syn::punctuated::Punctuated;
+use syn::{Expr, Lit, Meta, Token};
+use toml::Value;
+
+use crate::config;
+
 const CARGO_ENCODED_RUSTFLAGS: &str = "CARGO_ENCODED_RUSTFLAGS";
 const RUSTFLAGS: &str = "RUSTFLAGS";
+const CARGO_BUILD_RUSTFLAGS: &str = "CARGO_BUILD_RUSTFLAGS";
+const CARGO_BUILD_TARGET: &str = "CARGO_BUILD_TARGET";
 const IGNORED_LINTS: &[&str] = &["dead_code"];
 
 pub fn make_vec() -> Vec<String> {
@@ -16,29 +27,255 @@ pub fn make_vec() -> Vec<String> {
     rustflags
 }
 
-pub fn set_env(cmd: &mut Command) {
-    // The precedence of rustflags is:
-    // 1. CARGO_ENCODED_RUSTFLAGS
-    // 2. RUSTFLAGS
-    // 3. target.<triple>.rustflags (CARGO_TARGET_<triple>_RUSTFLAGS) and target.<cfg>.rustflags
-    // 4. build.rustflags (CARGO_BUILD_RUSTFLAGS)
-    // Refs: https://doc.rust-lang.org/nightly/cargo/reference/config.html#buildrustflags
-    // For now, skip 3 and 4 because 3 is complex and handling 4 without 3 incorrectly overwrite rustflags.
-    // TODO: Consider using cargo-config2 crate that implements it.
-    let (key, mut val, separator) = match env::var_os(CARGO_ENCODED_RUSTFLAGS) {
-        Some(val) => (CARGO_ENCODED_RUSTFLAGS, val, "\x1f"),
-        None => match env::var_os(RUSTFLAGS) {
-            Some(val) => (RUSTFLAGS, val, " "),
-            None => return,
-        },
+/// Returns the rustflags cargo would use for the source crate, followed by the ignored lints.
+///
+/// The generated crate lives in another directory, so its cargo configuration differs from
+/// the one of the source crate. The rustflags are resolved once and passed to every cargo
+/// invocation with `CARGO_ENCODED_RUSTFLAGS`, which takes precedence over all the other sources.
+pub(crate) fn resolve(source_dir: &Path) -> Vec<String> {
+    let config = config::get_build_config(source_dir);
+    let env = |key: &str| env::var_os(key).map(|v| v.to_string_lossy().into_owned());
+
+    let target = env(CARGO_BUILD_TARGET)
+        .or_else(|| get_str(&config, &["build", "target"]))
+        .or_else(host_triple);
+    let cfgs = target.as_deref().map(target_cfgs).unwrap_or_default();
+
+    let mut rustflags = from_sources(env, &config, target.as_deref(), &cfgs);
+    rustflags.extend(make_vec());
+    rustflags
+}
+
+pub fn set_env(cmd: &mut Command, rustflags: &[String]) {
+    cmd.env(CARGO_ENCODED_RUSTFLAGS, rustflags.join("\x1f"));
+}
+
+/// Selects the rustflags from the first of the sources that is set, in the order of precedence:
+/// 1. CARGO_ENCODED_RUSTFLAGS
+/// 2. RUSTFLAGS
+/// 3. target.<triple>.rustflags, CARGO_TARGET_<triple>_RUSTFLAGS and target.<cfg>.rustflags,
+///    joined together
+/// 4. build.rustflags and CARGO_BUILD_RUSTFLAGS, joined together
+///
+/// Refs: https://doc.rust-lang.org/nightly/cargo/reference/config.html#buildrustflags
+fn from_sources(
+    env: impl Fn(&str) -> Option<String>,
+    config: &Map<String, Value>,
+    target: Option<&str>,
+    cfgs: &[Cfg],
+) -> Vec<String> {
+    if let Some(val) = env(CARGO_ENCODED_RUSTFLAGS) {
+        return val
+            .split('\x1f')
+            .filter(|flag| !flag.is_empty())
+            .map(str::to_owned)
+            .collect();
+    }
+
+    if let Some(val) = env(RUSTFLAGS) {
+        return split(&val);
+    }
+
+    let mut target_rustflags = None;
+    if let Some(target) = target {
+        let key = format!(
+            "CARGO_TARGET_{}_RUSTFLAGS",
+            target.to_uppercase().replace(['-', '.'], "_")
+        );
+        target_rustflags = join(
+            get_flags(config, &["target", target, "rustflags"]),
+            env(&key).map(|val| split(&val)),
+        );
+    }
+    if let Some(Value::Table(targets)) = config.get("target") {
+        for (key, target) in targets {
+            if !key.starts_with("cfg(") || !cfg_matches(key, cfgs) {
+                continue;
+            }
+            if let Some(flags) = target.get("rustflags").and_then(to_flags) {
+                target_rustflags.get_or_insert_with(Vec::new).extend(flags);
+            }
+        }
+    }
+    if let Some(rustflags) = target_rustflags {
+        return rustflags;
+    }
+
+    join(
+        get_flags(config, &["build", "rustflags"]),
+        env(CARGO_BUILD_RUSTFLAGS).map(|val| split(&val)),
+    )
+    .unwrap_or_default()
+}
+
+/// Joins the rustflags of the configuration files and of the environment variable,
+/// as Cargo appends the environment to the configuration for array options.
+fn join(config: Option<Vec<String>>, env: Option<Vec<String>>) -> Option<Vec<String>> {
+    match (config, env) {
+        (Some(mut config), Some(env)) => {
+            config.extend(env);
+            Some(config)
+        }
+        (config, env) => config.or(env),
+    }
+}
+
+fn split(flags: &str) -> Vec<String> {
+    flags.split_whitespace().map(str::to_owned).collect()
+}
+
+fn get<'a>(config: &'a Map<String, Value>, keys: &[&str]) -> Option<&'a Value> {
+    let (first, rest) = keys.split_first()?;
+    rest.iter()
+        .try_fold(config.get(*first)?, |value, key| value.get(key))
+}
+
+fn get_str(config: &Map<String, Value>, keys: &[&str]) -> Option<String> {
+    get(config, keys)?.as_str().map(str::to_owned)
+}
+
+fn get_flags(config: &Map<String, Value>, keys: &[&str]) -> Option<Vec<String>> {
+    get(config, keys).and_then(to_flags)
+}
+
+/// Rustflags are given either as a space-separated string or as an array of strings.
+fn to_flags(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(flags) => Some(split(flags)),
+        Value::Array(flags) => Some(
+            flags
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// A configuration option of the target, e.g. `unix` or `target_os = "linux"`.
+type Cfg = (String, Option<String>);
+
+fn rustc() -> Command {
+    Command::new(env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")))
+}
+
+fn host_triple() -> Option<String> {
+    let output = rustc().arg("-vV").output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(str::to_owned)
+}
+
+fn target_cfgs(target: &str) -> Vec<Cfg> {
+    let output = match rustc()
+        .args(["--print", "cfg", "--target", target])
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
     };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((name, value)) => (name.to_owned(), Some(value.trim_matches('"').to_owned())),
+            None => (line.to_owned(), None),
+        })
+        .collect()
+}
 
-    for flag in make_vec() {
-        if !val.is_empty() {
-            val.push(separator);
+/// Evaluates a `cfg(...)` key of the `target` section against the configuration of the target.
+fn cfg_matches(key: &str, cfgs: &[Cfg]) -> bool {
+    match syn::parse_str::<Meta>(key) {
+        Ok(Meta::List(list)) if list.path.is_ident("cfg") => list
+            .parse_args::<Meta>()
+            .is_ok_and(|meta| eval(&meta, cfgs)),
+        _ => false,
+    }
+}
+
+fn eval(meta: &Meta, cfgs: &[Cfg]) -> bool {
+    match meta {
+        Meta::Path(path) => cfgs
+            .iter()
+            .any(|(name, value)| value.is_none() && path.is_ident(name)),
+        Meta::NameValue(name_value) => match &name_value.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(lit) => cfgs.iter().any(|(name, value)| {
+                    name_value.path.is_ident(name) && value.as_deref() == Some(&lit.value())
+                }),
+                _ => false,
+            },
+            _ => false,
+        },
+        Meta::List(list) => {
+            let nested = match list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            {
+                Ok(nested) => nested,
+                Err(_) => return false,
+            };
+            if list.path.is_ident("all") {
+                nested.iter().all(|meta| eval(meta, cfgs))
+            } else if list.path.is_ident("any") {
+                nested.iter().any(|meta| eval(meta, cfgs))
+            } else if list.path.is_ident("not") && nested.len() == 1 {
+                !eval(&nested[0], cfgs)
+            } else {
+                false
+            }
         }
-        val.push(flag);
     }
+}
+
+#[test]
+fn test_rustflags_precedence() {
+    let config = toml::from_str(
+        r#"
+        [build]
+        rustflags = "--cfg build"
+        [target.x86_64-unknown-linux-gnu]
+        rustflags = ["--cfg", "triple"]
+        [target.'cfg(all(unix, not(windows), target_os = "linux"))']
+        rustflags = ["--cfg", "unix"]
+        [target.'cfg(windows)']
+        rustflags = ["--cfg", "windows"]
+        "#,
+    )
+    .unwrap();
+    let cfgs = [
+        ("unix".to_owned(), None),
+        ("target_os".to_owned(), Some("linux".to_owned())),
+    ];
+    let linux = Some("x86_64-unknown-linux-gnu");
+
+    let no_env = |_: &str| None;
+    assert_eq!(
+        from_sources(no_env, &config, linux, &cfgs),
+        ["--cfg", "triple", "--cfg", "unix"]
+    );
+    assert_eq!(
+        from_sources(no_env, &config, Some("wasm32-unknown-unknown"), &[]),
+        ["--cfg", "build"]
+    );
+
+    let target_env = |key: &str| {
+        (key == "CARGO_TARGET_X86_64_UNKNOWN_LINUX_GNU_RUSTFLAGS").then(|| "--cfg env".to_owned())
+    };
+    assert_eq!(
+        from_sources(target_env, &config, linux, &cfgs),
+        ["--cfg", "triple", "--cfg", "env", "--cfg", "unix"]
+    );
+
+    let build_env = |key: &str| (key == CARGO_BUILD_RUSTFLAGS).then(|| "--cfg env".to_owned());
+    assert_eq!(
+        from_sources(build_env, &config, Some("wasm32-unknown-unknown"), &[]),
+        ["--cfg", "build", "--cfg", "env"]
+    );
 
-    cmd.env(key, val);
+    let rustflags_env = |key: &str| (key == RUSTFLAGS).then(|| "-C debuginfo=0".to_owned());
+    assert_eq!(
+        from_sources(rustflags_env, &config, linux, &cfgs),
+        ["-C", "debuginfo=0"]
+    );
 }
@@ -0,0 +1,97 @@
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+const BOM: char = '\u{feff}';
+
+/// Brings the text of an expansion into a canonical form:
+/// no byte order mark, `\n` line endings, no trailing whitespace,
+/// and exactly one newline at the end of non-empty text.
+pub(crate) fn normalize_text(text: &str) -> String {
+    let text = text.strip_prefix(BOM).unwrap_or(text);
+
+    let mut normalized = String::with_capacity(text.len());
+    // `lines` handles both `\n` and `\r\n` line endings.
+    for line in text.lines() {
+        normalized.push_str(line.trim_end());
+        normalized.push('\n');
+    }
+
+    let len = normalized.trim_end_matches('\n').len();
+    normalized.truncate(len);
+    if !normalized.is_empty() {
+        normalized.push('\n');
+    }
+
+    normalized
+}
+
+/// Reads the expected expansion from a `.expanded.rs` file.
+pub(crate) fn read(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)?;
+    let text = String::from_utf8(bytes)
+        .map_err(|e| Error::InvalidUtf8(path.to_owned(), e.utf8_error()))?;
+
+    Ok(normalize_text(&text))
+}
+
+/// Writes the expansion into a `.expanded.rs` file.
+///
+/// The content is written into a temporary file first and then renamed,
+/// so an interrupted run never leaves a truncated file behind.
+pub(crate) fn write(path: &Path, contents: &str) -> Result<()> {
+    let file_name = path.file_name().expect("no file name").to_string_lossy();
+    let random_string: String = iter::repeat_with(fastrand::alphanumeric).take(8).collect();
+    let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, random_string));
+
+    fs::write(&tmp_path, normalize_text(contents))?;
+    fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        Error::from(e)
+    })
+}
+
+/// An extension appended to the snapshot path for the pending snapshot.
+pub(crate) const PENDING_SUFFIX: &str = "new";
+
+/// Returns the path of the pending snapshot for the `.expanded.rs` file.
+///
+/// Pending snapshots are written next to the `.expanded.rs` files, or into the `pending_dir`
+/// mirroring the paths of the `.expanded.rs` files relative to the crate directory.
+pub(crate) fn pending_path(expanded: &Path, pending_dir: Option<&Path>) -> PathBuf {
+    let mut pending = match pending_dir {
+        Some(dir) => dir.join(expanded),
+        None => expanded.to_owned(),
+    }
+    .into_os_string();
+    pending.push(".");
+    pending.push(PENDING_SUFFIX);
+    PathBuf::from(pending)
+}
+
+/// Writes the expansion that differs from the `.expanded.rs` file as a pending snapshot,
+/// to be accepted or rejected with `cargo macrotest review`.
+pub(crate) fn write_pending(pending: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = pending.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    write(pending, contents)
+}
+
+/// Removes the pending snapshot, if any, once the expansion matches the `.expanded.rs` file.
+pub(crate) fn remove_pending(pending: &Path) -> Result<()> {
+    match fs::remove_file(pending) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        otherwise => Ok(otherwise?),
+    }
+}
+
+#[test]
+fn test_normalize_text() {
+    assert_eq!(normalize_text("\u{feff}fn a() {}\r\n"), "fn a() {}\n");
+    assert_eq!(normalize_text("fn a() {}  \n\n\n"), "fn a() {}\n");
+    assert_eq!(normalize_text("fn a() {}"), "fn a() {}\n");
+    assert_eq!(normalize_text("\n"), "");
+}
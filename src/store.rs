@@ -0,0 +1,302 @@
+use std::collections::BTreeMap as Map;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::snapshot;
+
+const MACROTEST_STORE: &str = "MACROTEST_STORE";
+
+/// The name of the file holding all the expansions of a glob pattern.
+pub(crate) const SINGLE_FILE_NAME: &str = "macrotest.snap";
+
+/// Starts the section of a single test in the snapshot file, followed by the path of the
+/// `.expanded.rs` file the section replaces, relative to the snapshot file.
+const SECTION_HEADER: &str = "//@ snapshot: ";
+
+const INTRO: &str =
+    "// Macro expansion snapshots written by macrotest, one section per test file.\n";
+
+/// Serializes updates of the snapshot files shared by the tests running in parallel.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Describes where the expansions are stored, set with the `MACROTEST_STORE` environment variable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum StoreKind {
+    /// A `.expanded.rs` file next to each test file.
+    Files,
+    /// A single `macrotest.snap` file for all the test files of a glob pattern.
+    SingleFile,
+}
+
+impl StoreKind {
+    /// Reads the kind of the store from the `MACROTEST_STORE` environment variable.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var_os(MACROTEST_STORE) {
+            Some(ref v) if v == "files" => Ok(StoreKind::Files),
+            Some(ref v) if v == "single-file" => Ok(StoreKind::SingleFile),
+            Some(v) => Err(Error::UnrecognizedEnv(MACROTEST_STORE, v)),
+            None => Ok(StoreKind::Files),
+        }
+    }
+}
+
+/// Reads and writes the expected expansions of the tests matching a glob pattern.
+///
+/// The expansions are addressed by the paths of their `.expanded.rs` files in both cases,
+/// so that the single-file store is a drop-in replacement for the sibling files.
+#[derive(Debug)]
+pub(crate) struct Store {
+    /// The snapshot file of the single-file store.
+    file: Option<PathBuf>,
+    pending_dir: Option<PathBuf>,
+}
+
+impl Store {
+    pub(crate) fn new(kind: StoreKind, pattern: &Path, pending_dir: Option<PathBuf>) -> Self {
+        let file = match kind {
+            StoreKind::Files => None,
+            StoreKind::SingleFile => Some(base_dir(pattern).join(SINGLE_FILE_NAME)),
+        };
+        Store { file, pending_dir }
+    }
+
+    /// Returns the expected expansion, if any.
+    pub(crate) fn read(&self, expanded: &Path) -> Result<Option<String>> {
+        let file = match &self.file {
+            Some(file) => file,
+            None if expanded.exists() => return snapshot::read(expanded).map(Some),
+            None => return Ok(None),
+        };
+
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(read_sections(file)?.remove(&self.key(file, expanded)))
+    }
+
+    /// Writes the expected expansion.
+    pub(crate) fn write(&self, expanded: &Path, contents: &str) -> Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return snapshot::write(expanded, contents),
+        };
+
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sections = read_sections(file)?;
+        sections.insert(self.key(file, expanded), snapshot::normalize_text(contents));
+        write_sections(file, &sections)
+    }
+
+    /// Removes the expected expansion.
+    pub(crate) fn remove(&self, expanded: &Path) -> Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(fs::remove_file(expanded)?),
+        };
+
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sections = read_sections(file)?;
+        sections.remove(&self.key(file, expanded));
+        write_sections(file, &sections)
+    }
+
+    /// Returns the paths of the `.expanded.rs` files stored in the snapshot file,
+    /// or `files` matched by the glob pattern if sibling files are used.
+    pub(crate) fn snapshots(&self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(files),
+        };
+
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let base = file.parent().unwrap_or_else(|| Path::new(""));
+        Ok(read_sections(file)?
+            .into_keys()
+            .map(|key| base.join(key))
+            .collect())
+    }
+
+    /// Returns the path of the file with the pending expansion.
+    pub(crate) fn pending_path(&self, expanded: &Path) -> PathBuf {
+        snapshot::pending_path(
+            self.file.as_deref().unwrap_or(expanded),
+            self.pending_dir.as_deref(),
+        )
+    }
+
+    /// Writes the expansion that differs from the expected one as pending.
+    ///
+    /// The pending snapshot file of the single-file store contains all the sections,
+    /// so that accepting it replaces the snapshot file.
+    pub(crate) fn write_pending(&self, expanded: &Path, contents: &str) -> Result<()> {
+        let pending = self.pending_path(expanded);
+        let file = match &self.file {
+            Some(file) => file,
+            None => return snapshot::write_pending(&pending, contents),
+        };
+
+        let key = self.key(file, expanded);
+        self.update_pending(file, &pending, |sections, _| {
+            sections.insert(key, snapshot::normalize_text(contents));
+        })
+    }
+
+    /// Removes the pending expansion, if any, once the expansion matches the expected one.
+    pub(crate) fn remove_pending(&self, expanded: &Path) -> Result<()> {
+        let pending = self.pending_path(expanded);
+        let file = match &self.file {
+            Some(file) => file,
+            None => return snapshot::remove_pending(&pending),
+        };
+
+        let key = self.key(file, expanded);
+        self.update_pending(file, &pending, |sections, expected| {
+            match expected.get(&key) {
+                Some(contents) => sections.insert(key, contents.clone()),
+                None => sections.remove(&key),
+            };
+        })
+    }
+
+    /// Applies `update` to the sections of the pending snapshot file, given the sections of the
+    /// snapshot file. The pending file starts as a copy of the snapshot file, and is removed
+    /// once it matches it again.
+    fn update_pending(
+        &self,
+        file: &Path,
+        pending: &Path,
+        update: impl FnOnce(&mut Map<String, String>, &Map<String, String>),
+    ) -> Result<()> {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let expected = read_sections(file)?;
+        let mut sections = if pending.exists() {
+            read_sections(pending)?
+        } else {
+            expected.clone()
+        };
+
+        update(&mut sections, &expected);
+
+        if sections == expected {
+            snapshot::remove_pending(pending)
+        } else {
+            snapshot::write_pending(pending, &render(&sections))
+        }
+    }
+
+    /// The key of the section of the `.expanded.rs` file: its path relative to the snapshot file.
+    fn key(&self, file: &Path, expanded: &Path) -> String {
+        let base = file.parent().unwrap_or_else(|| Path::new(""));
+        let relative = expanded.strip_prefix(base).unwrap_or(expanded);
+        relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Describes where the expansion is stored for messages.
+    pub(crate) fn location(&self, expanded: &Path) -> String {
+        match &self.file {
+            Some(file) => format!("{} [{}]", file.display(), self.key(file, expanded)),
+            None => expanded.display().to_string(),
+        }
+    }
+}
+
+/// The longest leading part of the glob pattern without wildcards, or the parent directory
+/// if the pattern is a path to a single file.
+fn base_dir(pattern: &Path) -> PathBuf {
+    let mut base = PathBuf::new();
+    for component in pattern.components() {
+        let wildcard = match component {
+            Component::Normal(part) => part.to_string_lossy().contains(['*', '?', '[']),
+            _ => false,
+        };
+        if wildcard {
+            return base;
+        }
+        base.push(component);
+    }
+    base.pop();
+    base
+}
+
+fn read_sections(file: &Path) -> Result<Map<String, String>> {
+    if !file.exists() {
+        return Ok(Map::new());
+    }
+    Ok(parse(&snapshot::read(file)?))
+}
+
+fn write_sections(file: &Path, sections: &Map<String, String>) -> Result<()> {
+    if sections.is_empty() {
+        return match fs::remove_file(file) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            otherwise => Ok(otherwise?),
+        };
+    }
+    snapshot::write(file, &render(sections))
+}
+
+/// Splits the snapshot file into sections. Lines before the first section are ignored.
+fn parse(text: &str) -> Map<String, String> {
+    let mut sections = Map::new();
+    let mut current: Option<(String, String)> = None;
+
+    for line in text.lines() {
+        if let Some(key) = line.strip_prefix(SECTION_HEADER) {
+            if let Some((key, contents)) = current.take() {
+                sections.insert(key, snapshot::normalize_text(&contents));
+            }
+            current = Some((key.trim().to_owned(), String::new()));
+        } else if let Some((_, contents)) = &mut current {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+    }
+    if let Some((key, contents)) = current {
+        sections.insert(key, snapshot::normalize_text(&contents));
+    }
+
+    sections
+}
+
+fn render(sections: &Map<String, String>) -> String {
+    let mut text = String::from(INTRO);
+    for (key, contents) in sections {
+        text.push('\n');
+        text.push_str(SECTION_HEADER);
+        text.push_str(key);
+        text.push('\n');
+        text.push_str(contents);
+    }
+    text
+}
+
+#[test]
+fn test_sections() {
+    let mut sections = Map::new();
+    sections.insert("a.expanded.rs".to_owned(), "fn a() {}\n".to_owned());
+    sections.insert(
+        "sub/b.expanded.rs".to_owned(),
+        "fn b() {}\n\nfn c() {}\n".to_owned(),
+    );
+    sections.insert("empty.expanded.rs".to_owned(), String::new());
+
+    assert_eq!(parse(&render(&sections)), sections);
+
+    assert_eq!(
+        base_dir(Path::new("tests/expand/*.rs")),
+        Path::new("tests/expand")
+    );
+    assert_eq!(
+        base_dir(Path::new("tests/pr61/*/*.rs")),
+        Path::new("tests/pr61")
+    );
+    assert_eq!(
+        base_dir(Path::new("tests/expand/a.rs")),
+        Path::new("tests/expand")
+    );
+}
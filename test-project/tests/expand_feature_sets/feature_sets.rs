@@ -0,0 +1,11 @@
+#[macro_use]
+extern crate test_project;
+
+pub fn main() {
+    test_vec![1, 2, 3];
+}
+
+#[cfg(feature = "test-feature")]
+pub fn with_feature() {
+    test_feature_vec![4, 5, 6];
+}
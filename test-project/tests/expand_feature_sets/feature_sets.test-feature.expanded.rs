@@ -0,0 +1,20 @@
+#[macro_use]
+extern crate test_project;
+pub fn main() {
+    {
+        let mut temp_vec = Vec::new();
+        temp_vec.push(1);
+        temp_vec.push(2);
+        temp_vec.push(3);
+        temp_vec
+    };
+}
+pub fn with_feature() {
+    {
+        let mut temp_vec = Vec::new();
+        temp_vec.push(4);
+        temp_vec.push(5);
+        temp_vec.push(6);
+        temp_vec
+    };
+}
@@ -0,0 +1,13 @@
+// The same test files may be expanded both with and without feature sets.
+// The snapshots of the feature sets must not be taken for orphaned files then.
+
+#[test]
+pub fn feature_sets_are_not_orphans() {
+    std::env::set_var("MACROTEST_ORPHANS", "fail");
+
+    macrotest::expand_feature_sets(
+        "tests/expand_feature_sets/*.rs",
+        &[("default", &["default"]), ("test-feature", &["test-feature"])],
+    );
+    macrotest::expand("tests/expand_feature_sets/*.rs");
+}
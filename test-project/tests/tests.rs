@@ -42,3 +42,22 @@ pub fn fail_expect_expanded_args() {
 pub fn pr61() {
     macrotest::expand("tests/pr61/*/*.rs");
 }
+
+#[test]
+pub fn pass_feature_sets() {
+    macrotest::expand_feature_sets(
+        "tests/expand_feature_sets/*.rs",
+        &[("default", &["default"]), ("test-feature", &["test-feature"])],
+    );
+}
+
+#[test]
+pub fn pass_feature_powerset() {
+    macrotest::expand_feature_powerset("tests/expand_feature_sets/*.rs");
+}
+
+#[test]
+pub fn pass_declared_features() {
+    macrotest::features!("default", "test-feature");
+    macrotest::expand("tests/expand/*.rs");
+}